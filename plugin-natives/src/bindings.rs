@@ -1,9 +1,23 @@
-use crate::{compress_packet, decompress_packet, jlong, jshort, JvmConstructable};
+use crate::{
+    classes, compress_packet, decompress_packet, jlong, jshort, JvmConstructable, ZLIB_ALGORITHM,
+};
 use common::packets::*;
+use common::PROTOCOL_VERSION;
 use jni::objects::JClass;
 use jni::sys::{jbyteArray, jobject};
 use jni::JNIEnv;
 
+#[no_mangle]
+pub extern "system" fn Java_io_github_personbelowrocks_minecraft_testgenerator_NativeBindings_init(
+    env: JNIEnv,
+    _class: JClass,
+) {
+    if let Err(error) = classes::init(&env) {
+        env.throw_new("java/lang/IllegalStateException", error.to_string())
+            .unwrap();
+    }
+}
+
 #[no_mangle]
 pub extern "system" fn Java_io_github_personbelowrocks_minecraft_testgenerator_NativeBindings_decodePacket(
     env: JNIEnv,
@@ -30,7 +44,7 @@ pub extern "system" fn Java_io_github_personbelowrocks_minecraft_testgenerator_N
         _ => panic!("invalid packet ID: {id}"),
     };
 
-    let jvm_class = env.find_class(desc.class).unwrap();
+    let jvm_class = classes::class(desc.class);
     let jvm_packet_obj = env
         .new_object(jvm_class, desc.ctor_sig, &desc.ctor_args.jvalue_buf())
         .unwrap();
@@ -62,7 +76,8 @@ pub extern "system" fn Java_io_github_personbelowrocks_minecraft_testgenerator_N
     let compressed_buffer = compress_packet(&decompressed_buffer);
     let compressed_len = compressed_buffer.len() as u32;
 
-    let mut buf = compressed_len.to_be_bytes().to_vec();
+    let mut buf = vec![PROTOCOL_VERSION, ZLIB_ALGORITHM];
+    buf.extend(compressed_len.to_be_bytes());
     buf.extend(decompressed_len.to_be_bytes());
     buf.extend(compressed_buffer);
 
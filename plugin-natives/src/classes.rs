@@ -0,0 +1,107 @@
+//! Cache of the JVM classes the natives construct objects of.
+//! The classes are looked up once when the plugin loads the natives (see [`init`]), so a missing class is reported
+//! right away with a descriptive error, instead of crashing the JVM the first time we try to construct an object of it.
+
+use std::collections::HashMap;
+
+use common::packets::{ConfirmGeneratorAddition, GenerateChunk, ReplyChunk};
+use common::{Chunk, ChunkSection};
+use jni::objects::{GlobalRef, JClass};
+use jni::JNIEnv;
+use once_cell::sync::OnceCell;
+
+use crate::JvmConstructable;
+
+/// Class of the `int[]` arrays that make up a chunk section's voxels.
+pub(crate) const INT_ARRAY_CLASS: &str = "[I";
+
+/// Every class that's looked up when the natives are initialized.
+const REQUIRED_CLASSES: [&str; 6] = [
+    GenerateChunk::CLASS,
+    ReplyChunk::CLASS,
+    ConfirmGeneratorAddition::CLASS,
+    Chunk::CLASS,
+    ChunkSection::CLASS,
+    INT_ARRAY_CLASS,
+];
+
+static CLASSES: OnceCell<HashMap<&'static str, GlobalRef>> = OnceCell::new();
+
+#[derive(te::Error, Debug)]
+pub(crate) enum ClassLookupError {
+    #[error(
+        "Required JVM class '{0}' could not be found (are the plugin and its natives out of sync?)"
+    )]
+    Missing(&'static str),
+}
+
+/// Look up every class in `names` with `find`, failing on the first class that can't be found.
+fn lookup_all<T, F>(
+    names: &[&'static str],
+    mut find: F,
+) -> Result<HashMap<&'static str, T>, ClassLookupError>
+where
+    F: FnMut(&'static str) -> Option<T>,
+{
+    names
+        .iter()
+        .map(|&name| {
+            find(name)
+                .map(|class| (name, class))
+                .ok_or(ClassLookupError::Missing(name))
+        })
+        .collect()
+}
+
+/// Look up and cache all the classes the natives need. This must be called (from the plugin's class loader) before
+/// any packets are decoded. Calling it again after it succeeded does nothing.
+pub(crate) fn init(env: &JNIEnv<'_>) -> Result<(), ClassLookupError> {
+    if CLASSES.get().is_some() {
+        return Ok(());
+    }
+
+    let classes = lookup_all(&REQUIRED_CLASSES, |name| match env.find_class(name) {
+        Ok(class) => env.new_global_ref(class).ok(),
+        Err(_) => {
+            // Failing to find a class leaves a pending NoClassDefFoundError, which we replace with our own error.
+            env.exception_clear().ok();
+            None
+        }
+    })?;
+
+    let _ = CLASSES.set(classes);
+    Ok(())
+}
+
+/// Get a cached class.
+///
+/// # Panics
+/// If [`init`] hasn't been called, or `name` isn't one of the classes cached by it.
+pub(crate) fn class<'a>(name: &str) -> JClass<'a> {
+    let classes = CLASSES
+        .get()
+        .expect("class cache is uninitialized, NativeBindings.init() must be called before using the natives");
+
+    let class = classes
+        .get(name)
+        .unwrap_or_else(|| panic!("class '{name}' is not cached"));
+
+    JClass::from(class.as_obj())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_all, ClassLookupError};
+
+    #[test]
+    fn missing_class_reported() {
+        let names = ["a/Present", "a/Missing", "a/AlsoPresent"];
+
+        let found = lookup_all(&names[..1], |name| Some(name.len())).unwrap();
+        assert_eq!(found["a/Present"], 9);
+
+        let error = lookup_all(&names, |name| (name != "a/Missing").then(|| ())).unwrap_err();
+        assert!(matches!(error, ClassLookupError::Missing("a/Missing")));
+        assert!(error.to_string().contains("'a/Missing'"));
+    }
+}
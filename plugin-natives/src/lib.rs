@@ -1,10 +1,12 @@
 extern crate nalgebra as na;
 extern crate procgen_common as common;
+extern crate thiserror as te;
 
 use common::packets::PacketBuffer;
+use common::BlockId;
 use common::Chunk;
 use common::ChunkSection;
-use common::CHUNK_SIZE;
+use common::{CHUNK_SECTION_VOLUME, CHUNK_SIZE};
 use flate2::read::ZlibDecoder;
 use flate2::read::ZlibEncoder;
 use flate2::Compression;
@@ -16,11 +18,19 @@ use jni::JNIEnv;
 use std::io::Read;
 
 pub mod bindings;
+mod classes;
 mod packets;
 
-fn decompress_packet(bytes: &[u8], _size_hint: usize) -> PacketBuffer {
+/// ID of zlib in packet headers. The natives only compress packets with zlib, so the plugin must reject packets
+/// compressed with anything else.
+const ZLIB_ALGORITHM: u8 = 0;
+
+fn decompress_packet(bytes: &[u8], size_hint: usize) -> PacketBuffer {
     let mut reader = ZlibDecoder::new(bytes);
-    PacketBuffer::from_reader(&mut reader).unwrap()
+    let mut buf = Vec::with_capacity(size_hint);
+
+    reader.read_to_end(&mut buf).unwrap();
+    PacketBuffer::from_vec(buf).unwrap()
 }
 
 fn compress_packet(bytes: &PacketBuffer) -> Vec<u8> {
@@ -31,6 +41,79 @@ fn compress_packet(bytes: &PacketBuffer) -> Vec<u8> {
     buf
 }
 
+/// Flatten an initialized section's voxels into a buffer with the same layout as the JVM's `[[[I` array,
+/// so that the voxel at `[x, y, z]` is at index `x * 16 * 16 + y * 16 + z`.
+/// Doing this in one pass lets us fill the JVM array with one copy per pole, instead of building a buffer for every pole.
+fn section_as_jint_buffer(section: &ChunkSection) -> Option<Vec<jint>> {
+    let storage = section.inner_ref()?;
+    let mut buf = Vec::with_capacity(CHUNK_SECTION_VOLUME);
+
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let id = storage[(x + y * CHUNK_SIZE + z * CHUNK_SIZE * CHUNK_SIZE) as usize];
+                buf.push(i32::from_be_bytes(u32::from(id).to_be_bytes()));
+            }
+        }
+    }
+
+    Some(buf)
+}
+
+/// Build a section out of a buffer laid out like the one returned by [`section_as_jint_buffer`].
+/// Returns `None` if the buffer doesn't contain exactly one section's worth of voxels.
+fn section_from_jint_buffer(default: BlockId, buf: &[jint]) -> Option<ChunkSection> {
+    if buf.len() != CHUNK_SECTION_VOLUME {
+        return None;
+    }
+
+    // The JVM's layout has Z changing fastest, but `fill_from_slice` wants X changing fastest.
+    let size = CHUNK_SIZE as usize;
+    let mut voxels = [default; CHUNK_SECTION_VOLUME];
+    for (i, &id) in buf.iter().enumerate() {
+        let (x, y, z) = (i / (size * size), (i / size) % size, i % size);
+        voxels[x + y * size + z * size * size] = u32::from_be_bytes(id.to_be_bytes()).into();
+    }
+
+    let mut section = ChunkSection::new_uninitialized(default);
+    section.fill_from_slice(&voxels);
+    Some(section)
+}
+
+/// Read a `[[[I` array from the JVM into a buffer with the layout described in [`section_as_jint_buffer`].
+/// Returns `None` if the array isn't 16x16x16.
+fn read_jvm_voxels(env: &JNIEnv<'_>, cubic: JObject<'_>) -> Option<Vec<jint>> {
+    let size = CHUNK_SIZE as usize;
+    let mut buf = vec![0; CHUNK_SECTION_VOLUME];
+
+    if env.get_array_length(cubic.into_inner()).ok()? != CHUNK_SIZE {
+        return None;
+    }
+
+    for (x, sheet_voxels) in buf.chunks_mut(size * size).enumerate() {
+        let sheet = env
+            .get_object_array_element(cubic.into_inner(), x as _)
+            .ok()?;
+        if sheet.is_null() || env.get_array_length(sheet.into_inner()).ok()? != CHUNK_SIZE {
+            return None;
+        }
+
+        for (y, pole_voxels) in sheet_voxels.chunks_mut(size).enumerate() {
+            let pole = env
+                .get_object_array_element(sheet.into_inner(), y as _)
+                .ok()?;
+            if pole.is_null() || env.get_array_length(pole.into_inner()).ok()? != CHUNK_SIZE {
+                return None;
+            }
+
+            env.get_int_array_region(pole.into_inner(), 0, pole_voxels)
+                .ok()?;
+        }
+    }
+
+    Some(buf)
+}
+
 struct JvmConstructableDesc<'a> {
     class: &'static str,
     ctor_sig: String,
@@ -167,17 +250,21 @@ impl JvmConstructable for ChunkSection {
     const CLASS: &'static str = "io/github/personbelowrocks/minecraft/testgenerator/ChunkSection";
 
     fn ctor_args<'a>(&self, env: &JNIEnv<'a>) -> CtorArgs<'a> {
-        if !self.is_initialized() {
-            let mut args = CtorArgs::new();
-            args.add(QualifiedJValue::Object(NamedJObject::new(
-                "[[[I".into(),
-                (std::ptr::null::<u8>() as jobject).into(),
-            )));
-
-            return args;
-        }
+        let voxels = match section_as_jint_buffer(self) {
+            Some(voxels) => voxels,
+            None => {
+                let mut args = CtorArgs::new();
+                args.add(QualifiedJValue::Object(NamedJObject::new(
+                    "[[[I".into(),
+                    (std::ptr::null::<u8>() as jobject).into(),
+                )))
+                .add(QualifiedJValue::Long(self.default_id().0.into()));
+
+                return args;
+            }
+        };
 
-        let cls = env.find_class("[I").unwrap();
+        let cls = classes::class(classes::INT_ARRAY_CLASS);
 
         let pole = env.new_int_array(CHUNK_SIZE as _).unwrap();
         let sheet = env.new_object_array(CHUNK_SIZE as _, cls, pole).unwrap();
@@ -185,17 +272,14 @@ impl JvmConstructable for ChunkSection {
         let cubic = env
             .new_object_array(CHUNK_SIZE as _, env.get_object_class(sheet).unwrap(), sheet)
             .unwrap();
-        for x in 0..CHUNK_SIZE {
+        let sheet_len = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+        for (x, sheet_voxels) in voxels.chunks(sheet_len).enumerate() {
             let sheet = env.new_object_array(CHUNK_SIZE as _, cls, pole).unwrap();
 
-            for y in 0..CHUNK_SIZE {
+            for (y, pole_voxels) in sheet_voxels.chunks(CHUNK_SIZE as usize).enumerate() {
                 let pole = env.new_int_array(CHUNK_SIZE as _).unwrap();
-                let buf = (0..CHUNK_SIZE)
-                    .map(|z| self.inner_ref().unwrap()[[x, y, z]])
-                    .map(|b| i32::from_be_bytes(u32::from(b).to_be_bytes()))
-                    .collect::<Vec<i32>>();
 
-                env.set_int_array_region(pole, 0, &buf).unwrap();
+                env.set_int_array_region(pole, 0, pole_voxels).unwrap();
                 env.set_object_array_element(sheet, y as _, pole).unwrap();
             }
 
@@ -206,13 +290,33 @@ impl JvmConstructable for ChunkSection {
         args.add(QualifiedJValue::Object(NamedJObject::new(
             "[[[I".into(),
             cubic.into(),
-        )));
+        )))
+        .add(QualifiedJValue::Long(self.default_id().0.into()));
 
         args
     }
 
-    fn from_jvm_obj(_env: &JNIEnv<'_>, _obj: jni::objects::JObject<'_>) -> Option<Self> {
-        todo!()
+    fn from_jvm_obj(env: &JNIEnv<'_>, obj: jni::objects::JObject<'_>) -> Option<Self> {
+        if !env.is_instance_of(obj, classes::class(Self::CLASS)).ok()? {
+            return None;
+        }
+
+        match (
+            env.call_method(obj, "getInternal", "()[[[I", &[]).ok()?,
+            env.call_method(obj, "getDefaultId", "()J", &[]).ok()?,
+        ) {
+            (JValue::Object(cubic), JValue::Long(default_id)) => {
+                let default = BlockId::from(default_id as u32);
+
+                // Uninitialized sections are sent to the JVM without an array.
+                if cubic.is_null() {
+                    return Some(ChunkSection::new_uninitialized(default));
+                }
+
+                section_from_jint_buffer(default, &read_jvm_voxels(env, cubic)?)
+            }
+            _ => None,
+        }
     }
 }
 
@@ -220,7 +324,7 @@ impl JvmConstructable for Chunk {
     const CLASS: &'static str = "io/github/personbelowrocks/minecraft/testgenerator/Chunk";
 
     fn ctor_args<'a>(&self, env: &JNIEnv<'a>) -> CtorArgs<'a> {
-        let section_cls = env.find_class(ChunkSection::CLASS).unwrap();
+        let section_cls = classes::class(ChunkSection::CLASS);
 
         let sections = self
             .sections()
@@ -261,7 +365,97 @@ impl JvmConstructable for Chunk {
         args
     }
 
-    fn from_jvm_obj(_env: &JNIEnv<'_>, _obj: jni::objects::JObject<'_>) -> Option<Self> {
-        todo!()
+    fn from_jvm_obj(env: &JNIEnv<'_>, obj: jni::objects::JObject<'_>) -> Option<Self> {
+        if !env.is_instance_of(obj, classes::class(Self::CLASS)).ok()? {
+            return None;
+        }
+
+        let coord = |name: &str| match env.call_method(obj, name, "()J", &[]).ok()? {
+            JValue::Long(coord) => Some(coord),
+            _ => None,
+        };
+        let (x1, y1, z1, y2) = (
+            coord("getX1")?,
+            coord("getY1")?,
+            coord("getZ1")?,
+            coord("getY2")?,
+        );
+
+        let jvm_sections = match env
+            .call_method(
+                obj,
+                "getSections",
+                format!("()[L{};", ChunkSection::CLASS),
+                &[],
+            )
+            .ok()?
+        {
+            JValue::Object(sections) if !sections.is_null() => sections,
+            _ => return None,
+        };
+
+        let n_sections = env.get_array_length(jvm_sections.into_inner()).ok()?;
+        let sections = (0..n_sections)
+            .map(|i| {
+                let section = env
+                    .get_object_array_element(jvm_sections.into_inner(), i)
+                    .ok()?;
+                ChunkSection::from_jvm_obj(env, section)
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let pos = na::vector![x1 as i32, z1 as i32] / CHUNK_SIZE;
+        Chunk::from_sections(sections, pos, y1 as i32, y2 as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{BlockId, ChunkSection, CHUNK_SIZE};
+    use volume::Volume;
+
+    use super::{section_as_jint_buffer, section_from_jint_buffer};
+
+    #[test]
+    fn jint_buffer_matches_jvm_layout() {
+        let mut section = ChunkSection::new_initialized(BlockId::new(5));
+        section.set([1i32, 2, 3], BlockId::new(42));
+        section.set([15i32, 0, 7], BlockId::new(u32::MAX));
+
+        let buf = section_as_jint_buffer(&section).unwrap();
+
+        // This is the layout the old implementation produced, one pole of Z values per (X, Y) pair.
+        let mut expected = Vec::new();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                expected.extend(
+                    (0..CHUNK_SIZE)
+                        .map(|z| *section.get([x, y, z]).unwrap())
+                        .map(|b| i32::from_be_bytes(u32::from(b).to_be_bytes())),
+                );
+            }
+        }
+
+        assert_eq!(buf, expected);
+        assert_eq!(buf[(16 * 16) + (2 * 16) + 3], 42);
+        assert_eq!(buf[(15 * 16 * 16) + 7], -1);
+
+        assert!(
+            section_as_jint_buffer(&ChunkSection::new_uninitialized(BlockId::new(5))).is_none()
+        );
+    }
+    #[test]
+    fn jint_buffer_round_trip() {
+        let mut section = ChunkSection::new_initialized(BlockId::new(5));
+        section.set([1i32, 2, 3], BlockId::new(42));
+        section.set([15i32, 0, 7], BlockId::new(u32::MAX));
+
+        let buf = section_as_jint_buffer(&section).unwrap();
+        let section_copy = section_from_jint_buffer(BlockId::new(5), &buf).unwrap();
+
+        assert!(section_copy == section);
+        assert_eq!(section_copy.default_id(), BlockId::new(5));
+
+        assert!(section_from_jint_buffer(BlockId::new(5), &buf[1..]).is_none());
     }
 }
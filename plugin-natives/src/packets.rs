@@ -1,4 +1,4 @@
-use crate::{CtorArgs, JvmConstructable, NamedJObject, QualifiedJValue};
+use crate::{classes, CtorArgs, JvmConstructable, NamedJObject, QualifiedJValue};
 use common::packets::*;
 use jni::objects::JValue;
 use jni::JNIEnv;
@@ -51,7 +51,7 @@ impl JvmConstructable for ReplyChunk {
         let chunk_ctor_args = self.chunk.ctor_args(env);
         let chunk_obj = env
             .new_object(
-                env.find_class(Chunk::CLASS).unwrap(),
+                classes::class(Chunk::CLASS),
                 chunk_ctor_args.signature(),
                 &chunk_ctor_args.jvalue_buf(),
             )
@@ -112,6 +112,8 @@ impl JvmConstructable for AddGenerator {
                 min_height,
                 max_height,
                 default_id: (default_id as u32).into(),
+                // The plugin doesn't send generator parameters yet.
+                params: Default::default(),
             }),
             _ => None,
         }
@@ -1,6 +1,10 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
 use vol::prelude::*;
 
-use super::section::ChunkSection;
+use super::section::{ChunkSection, CHUNK_SECTION_VOLUME};
 use crate::{BlockId, IVec2, IVec3};
 
 /// X and Z dimensions of chunks (taken from Minecraft)
@@ -10,6 +14,23 @@ pub const CHUNK_SIZE: i32 = 16;
 /// This constant is here for ergonomics so you can do add it to a chunk section's position and get the position of the opposite corner.
 pub const CHUNK_SECTION_CORNER: IVec3 = na::vector![CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE];
 
+/// Pack a localspace position in a chunk into a single index (X changes fastest, then Z, then Y).
+#[inline]
+fn pack_local_index(x: i32, y: i32, z: i32) -> u32 {
+    (x + z * CHUNK_SIZE + y * CHUNK_SIZE * CHUNK_SIZE) as u32
+}
+
+/// Inverse of [`pack_local_index`].
+#[inline]
+fn unpack_local_index(index: u32) -> [i32; 3] {
+    let index = index as i32;
+    [
+        index % CHUNK_SIZE,
+        index / (CHUNK_SIZE * CHUNK_SIZE),
+        (index / CHUNK_SIZE) % CHUNK_SIZE,
+    ]
+}
+
 pub(super) fn chunk_sections_for_height(height: i32) -> usize {
     debug_assert!(height >= 0); // we can't have chunks with a negative height
 
@@ -47,6 +68,25 @@ impl Chunk {
         }
     }
 
+    /// Create a chunk at `chunk_pos` out of existing `sections`, ordered from the bottom of the chunk to the top.
+    /// Returns `None` if the number of sections doesn't match the height between `min_height` and `max_height`.
+    pub fn from_sections(
+        sections: Vec<ChunkSection>,
+        chunk_pos: IVec2,
+        min_height: i32,
+        max_height: i32,
+    ) -> Option<Self> {
+        if sections.is_empty()
+            || sections.len() != chunk_sections_for_height((min_height - max_height).abs())
+        {
+            return None;
+        }
+
+        let mut chunk = Self::new(sections[0].default_id(), chunk_pos, min_height, max_height);
+        chunk.sections = sections;
+        Some(chunk)
+    }
+
     #[inline]
     fn get_chunk_section(&self, chunk_section_idx: usize) -> Option<&ChunkSection> {
         self.sections.get(chunk_section_idx)
@@ -71,6 +111,120 @@ impl Chunk {
     pub fn sections(&self) -> &[ChunkSection] {
         &self.sections
     }
+
+    /// Count how many voxels of each block ID this chunk contains. Handy for checking what a generator produced.
+    pub fn block_histogram(&self) -> HashMap<BlockId, usize> {
+        let mut histogram = HashMap::new();
+
+        for section in self.sections.iter() {
+            // Uninitialized sections are entirely made up of their default block.
+            if !section.is_initialized() {
+                *histogram.entry(section.default_id()).or_insert(0) += CHUNK_SECTION_VOLUME;
+                continue;
+            }
+
+            for x in 0..CHUNK_SIZE {
+                for y in 0..CHUNK_SIZE {
+                    for z in 0..CHUNK_SIZE {
+                        *histogram
+                            .entry(*section.get([x, y, z]).unwrap())
+                            .or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        histogram
+    }
+
+    /// Hash the contents (i.e., the voxels) of this chunk, ignoring its position.
+    /// Chunks with the same contents have the same content hash regardless of where they are, which makes this useful
+    /// for caching and deduplicating generated chunks (lots of chunks are just air, for example).
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.sections.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The position of this chunk in chunk coordinates.
+    #[inline]
+    pub fn pos(&self) -> IVec2 {
+        let [x, _, z] = self.bounding_box().min();
+        na::vector![x as i32, z as i32] / CHUNK_SIZE
+    }
+
+    /// Move this chunk to the chunk position `new_pos`, keeping its voxels and height.
+    /// The voxel that was at localspace position `[x, y, z]` is still at `[x, y, z]` afterwards, so its worldspace
+    /// position moves along with the chunk.
+    pub fn relocate(mut self, new_pos: IVec2) -> Self {
+        let [min_x, min_y, min_z] = self.bounding_box.min();
+        let [max_x, max_y, max_z] = self.bounding_box.max();
+
+        let pos = new_pos * CHUNK_SIZE;
+        let (dx, dz) = (pos.x as i64 - min_x, pos.y as i64 - min_z);
+
+        self.bounding_box = BoundingBox::new(
+            [pos.x, min_y as i32, pos.y],
+            [(max_x + dx) as i32, max_y as i32, (max_z + dz) as i32],
+        );
+        self
+    }
+
+    /// Move this chunk by `delta` chunks along the X and Z axes, see [`Chunk::relocate`].
+    #[inline]
+    pub fn translate(self, delta: IVec2) -> Self {
+        let new_pos = self.pos() + delta;
+        self.relocate(new_pos)
+    }
+
+    /// Get the voxels in this chunk that differ from the voxels at the same localspace positions in `base`.
+    /// Each change is an `(index, block)` pair, where `index` is the voxel's localspace position packed so that
+    /// X changes fastest, then Z, then Y (i.e., `x + z * 16 + y * 16 * 16`). Changes are ordered by index.
+    ///
+    /// Applying the changes to `base` with [`Chunk::apply_delta`] reconstructs this chunk, which is a lot cheaper to send
+    /// than the whole chunk if only a few voxels changed. Returns `None` if the chunks have different heights.
+    pub fn diff(&self, base: &Chunk) -> Option<Vec<(u32, BlockId)>> {
+        if self.sections.len() != base.sections.len() {
+            return None;
+        }
+
+        let mut changes = Vec::new();
+
+        for (section_idx, (section, base_section)) in
+            self.sections.iter().zip(base.sections.iter()).enumerate()
+        {
+            // Uninitialized sections with the same default are identical, so there's no need to compare their voxels.
+            if !section.is_initialized()
+                && !base_section.is_initialized()
+                && section.default_id() == base_section.default_id()
+            {
+                continue;
+            }
+
+            let section_y = section_idx as i32 * CHUNK_SIZE;
+
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    for x in 0..CHUNK_SIZE {
+                        let id = section.get([x, y, z]).unwrap();
+
+                        if id != base_section.get([x, y, z]).unwrap() {
+                            changes.push((pack_local_index(x, section_y + y, z), *id));
+                        }
+                    }
+                }
+            }
+        }
+
+        Some(changes)
+    }
+
+    /// Apply changes produced by [`Chunk::diff`] to this chunk. Changes with indices outside of this chunk are ignored.
+    pub fn apply_delta(&mut self, changes: &[(u32, BlockId)]) {
+        for &(index, id) in changes {
+            self.set(unpack_local_index(index), id);
+        }
+    }
 }
 
 impl<Idx: VolumeIdx> VolumeAccess<Idx> for Chunk {
@@ -198,6 +352,16 @@ impl Volume for Chunk {
     type Item = BlockId;
 }
 
+impl std::cmp::Eq for Chunk {}
+
+impl Hash for Chunk {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sections.hash(state);
+        self.bounding_box.min().hash(state);
+        self.bounding_box.max().hash(state);
+    }
+}
+
 impl std::fmt::Debug for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Chunk")
@@ -1,4 +1,4 @@
-use vol::builtins::*;
+use vol::prelude::*;
 
 use crate::BlockId;
 
@@ -6,8 +6,24 @@ use super::basic::CHUNK_SIZE;
 
 const CHUNK_SIZE_USIZE: usize = CHUNK_SIZE as usize;
 
-type CubicVolume<const N: usize, T> = StackVolume<N, N, N, T>;
-type ChunkSectionStorage = CubicVolume<CHUNK_SIZE_USIZE, BlockId>;
+/// Number of voxels in a chunk section.
+pub const CHUNK_SECTION_VOLUME: usize = CHUNK_SIZE_USIZE.pow(3);
+
+/// Voxels of an initialized section, laid out like [`ChunkSection::fill_from_slice`] expects (X changes fastest,
+/// then Y, then Z), so filling a section from a slice is a single copy.
+pub type ChunkSectionStorage = Box<[BlockId; CHUNK_SECTION_VOLUME]>;
+
+/// Index of the voxel at `[x, y, z]` in a [`ChunkSectionStorage`], or `None` if the position is outside the section.
+#[inline]
+fn storage_index<Idx: VolumeIdx>(idx: Idx) -> Option<usize> {
+    let [x, y, z] = idx.array::<usize>()?;
+
+    if x < CHUNK_SIZE_USIZE && y < CHUNK_SIZE_USIZE && z < CHUNK_SIZE_USIZE {
+        Some(x + y * CHUNK_SIZE_USIZE + z * CHUNK_SIZE_USIZE * CHUNK_SIZE_USIZE)
+    } else {
+        None
+    }
+}
 
 /// A 16x16x16 cube of voxels/blocks.
 #[derive(Clone)]
@@ -36,7 +52,7 @@ impl ChunkSection {
     fn initialize(&mut self) {
         // Only do this if we're uninitialized so we avoid wiping any existing data.
         if !self.is_initialized() {
-            self.volume = Some(ChunkSectionStorage::filled(self.default));
+            self.volume = Some(Box::new([self.default; CHUNK_SECTION_VOLUME]));
         }
     }
 
@@ -50,6 +66,31 @@ impl ChunkSection {
         self.default
     }
 
+    /// Fill the entire section with `id`, initializing it if it isn't already.
+    ///
+    /// An initialized section is filled in place with [`slice::fill`], which reuses its storage instead of
+    /// allocating a new array.
+    #[inline]
+    pub fn fill(&mut self, id: BlockId) {
+        match self.volume {
+            Some(ref mut volume) => volume.fill(id),
+            None => self.volume = Some(Box::new([id; CHUNK_SECTION_VOLUME])),
+        }
+    }
+
+    /// Fill the section with the voxels in `data`, initializing it if it isn't already.
+    /// `data` is laid out in the same order that sections are serialized in, so X changes fastest and Z slowest
+    /// (i.e., the voxel at `[x, y, z]` is `data[x + y * 16 + z * 16 * 16]`).
+    ///
+    /// Since the section's storage has the same layout, this is just a copy of `data`.
+    pub fn fill_from_slice(&mut self, data: &[BlockId; CHUNK_SECTION_VOLUME]) {
+        match self.volume {
+            Some(ref mut volume) => volume.copy_from_slice(data),
+            // Don't bother filling new storage with the default block when we're about to overwrite all of it.
+            None => self.volume = Some(Box::new(*data)),
+        }
+    }
+
     #[inline]
     pub fn inner_ref(&self) -> Option<&ChunkSectionStorage> {
         self.volume.as_ref()
@@ -78,48 +119,55 @@ impl std::cmp::PartialEq for ChunkSection {
     }
 }
 
+impl std::cmp::Eq for ChunkSection {}
+
+impl std::hash::Hash for ChunkSection {
+    /// Hashes the section's voxels, so (like [`PartialEq`]) an uninitialized section hashes the same as an
+    /// initialized section filled with the same block.
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    self.get([x, y, z]).hash(state);
+                }
+            }
+        }
+    }
+}
+
 impl<Idx: VolumeIdx> VolumeAccess<Idx> for ChunkSection {
     #[inline]
     fn get(this: &Self, idx: Idx) -> Option<&Self::Item> {
+        let index = storage_index(idx)?;
+
         match this.volume {
-            Some(ref v) => v.get(idx),
-            None => {
-                if this.contains(idx) {
-                    Some(&this.default)
-                } else {
-                    None
-                }
-            }
+            Some(ref v) => Some(&v[index]),
+            None => Some(&this.default),
         }
     }
 
     #[inline]
     fn set(this: &mut Self, idx: Idx, item: Self::Item) {
-        if !this.is_initialized() {
+        if let Some(index) = storage_index(idx) {
             this.initialize();
-            this.volume.as_mut().unwrap().set(idx, item);
-        } else {
-            this.volume.as_mut().unwrap().set(idx, item);
+            this.volume.as_mut().unwrap()[index] = item;
         }
     }
 
     #[inline]
     fn swap(this: &mut Self, idx: Idx, item: Self::Item) -> Option<Self::Item> {
-        if !this.is_initialized() {
-            this.initialize();
-            this.volume.as_mut().unwrap().swap(idx, item)
-        } else {
-            this.volume.as_mut().unwrap().swap(idx, item)
-        }
+        let index = storage_index(idx)?;
+        this.initialize();
+
+        Some(std::mem::replace(
+            &mut this.volume.as_mut().unwrap()[index],
+            item,
+        ))
     }
 
     #[inline]
     fn contains(_this: &Self, idx: Idx) -> bool {
-        if let Some([x, y, z]) = idx.array::<usize>() {
-            x < CHUNK_SIZE_USIZE && y < CHUNK_SIZE_USIZE && z < CHUNK_SIZE_USIZE
-        } else {
-            false
-        }
+        storage_index(idx).is_some()
     }
 }
 
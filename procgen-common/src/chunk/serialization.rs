@@ -6,34 +6,52 @@ use vol::prelude::*;
 use crate::BlockId;
 
 use super::basic::{chunk_sections_for_height, CHUNK_SIZE};
-use super::section::ChunkSection;
+use super::section::{ChunkSection, CHUNK_SECTION_VOLUME};
 use super::Chunk;
 
+/// A run of `count` identical voxels. Initialized sections are serialized as a sequence of these (in the same order
+/// as [`ChunkSection::fill_from_slice`] expects), since most sections are made up of large runs of the same block.
+type VoxelRun = (u16, BlockId);
+
+impl ChunkSection {
+    fn voxel_runs(&self) -> Vec<VoxelRun> {
+        let mut runs = Vec::<VoxelRun>::new();
+
+        for z in 0..CHUNK_SIZE as usize {
+            for y in 0..CHUNK_SIZE as usize {
+                for x in 0..CHUNK_SIZE as usize {
+                    let id = *self.get([x, y, z]).unwrap();
+
+                    match runs.last_mut() {
+                        Some((count, last_id)) if *last_id == id => *count += 1,
+                        _ => runs.push((1, id)),
+                    }
+                }
+            }
+        }
+
+        runs
+    }
+}
+
 impl Serialize for ChunkSection {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let seq_len = {
-            2 + if self.is_initialized() {
-                (CHUNK_SIZE as usize).pow(3)
-            } else {
-                0
-            }
+        let runs = if self.is_initialized() {
+            self.voxel_runs()
+        } else {
+            Vec::new()
         };
-        let mut ser_seq = serializer.serialize_seq(Some(seq_len))?;
+
+        let mut ser_seq = serializer.serialize_seq(Some(2 + runs.len()))?;
 
         ser_seq.serialize_element(&self.default_id())?;
         ser_seq.serialize_element(&self.is_initialized())?;
 
-        if self.is_initialized() {
-            for z in 0..CHUNK_SIZE as usize {
-                for y in 0..CHUNK_SIZE as usize {
-                    for x in 0..CHUNK_SIZE as usize {
-                        ser_seq.serialize_element(self.get([x, y, z]).unwrap())?;
-                    }
-                }
-            }
+        for run in runs.iter() {
+            ser_seq.serialize_element(run)?;
         }
 
         ser_seq.end()
@@ -68,21 +86,30 @@ impl<'de> Visitor<'de> for ChunkSectionVisitor {
             return Ok(ChunkSection::new_uninitialized(default_id));
         }
 
-        let mut section = ChunkSection::new_initialized(default_id);
+        let mut voxels = [default_id; CHUNK_SECTION_VOLUME];
+        let mut filled = 0;
 
-        for z in 0..CHUNK_SIZE {
-            for y in 0..CHUNK_SIZE {
-                for x in 0..CHUNK_SIZE {
-                    let voxel = seq
-                        .next_element::<BlockId>()?
-                        .ok_or_else(|| A::Error::custom("voxel sequence terminated prematurely"))?;
+        while filled < CHUNK_SECTION_VOLUME {
+            let (count, id) = seq
+                .next_element::<VoxelRun>()?
+                .ok_or_else(|| A::Error::custom("voxel sequence terminated prematurely"))?;
 
-                    section.set([x, y, z], voxel)
-                }
+            let end = filled + count as usize;
+            if count == 0 || end > CHUNK_SECTION_VOLUME {
+                return Err(A::Error::custom(format!(
+                    "invalid voxel run of length {} starting at voxel {}",
+                    count, filled
+                )));
             }
+
+            voxels[filled..end].fill(id);
+            filled = end;
         }
 
-        if seq.next_element::<BlockId>()?.is_some() {
+        let mut section = ChunkSection::new_uninitialized(default_id);
+        section.fill_from_slice(&voxels);
+
+        if seq.next_element::<VoxelRun>()?.is_some() {
             Err(A::Error::custom("sequence was too long!"))
         } else {
             Ok(section)
@@ -104,10 +131,7 @@ impl Serialize for Chunk {
     where
         S: serde::Serializer,
     {
-        let pos = {
-            let [x, _, z] = self.bounding_box().min();
-            na::vector![x as i32, z as i32] / CHUNK_SIZE
-        };
+        let pos = self.pos();
 
         let min_height = self.bounding_box().min()[1] as i32;
         let max_height = self.bounding_box().max()[1] as i32;
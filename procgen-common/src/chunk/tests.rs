@@ -3,7 +3,7 @@ use volume::Volume;
 use crate::{
     chunk::{
         basic::{Spaces, CHUNK_SIZE},
-        section::ChunkSection,
+        section::{ChunkSection, CHUNK_SECTION_VOLUME},
     },
     BlockId, Chunk,
 };
@@ -133,3 +133,256 @@ fn chunk_bincode_mirror() {
         }
     }
 }
+
+#[test]
+fn chunk_from_sections() {
+    let mut sections = vec![ChunkSection::new_uninitialized(BlockId::new(3)); 2];
+    sections[1].set([1i32, 2, 3], BlockId::new(9));
+
+    let chunk = Chunk::from_sections(sections.clone(), na::vector![-2, 5], 0, 32).unwrap();
+
+    assert_eq!(chunk.pos(), na::vector![-2, 5]);
+    assert_eq!(chunk.default_id(), BlockId::new(3));
+    assert_eq!(chunk.get(Spaces::Cs([1i32, 18, 3])), Some(&BlockId::new(9)));
+
+    assert!(Chunk::from_sections(sections, na::vector![0, 0], 0, 64).is_none());
+    assert!(Chunk::from_sections(Vec::new(), na::vector![0, 0], 0, 0).is_none());
+}
+
+#[test]
+fn chunk_section_bulk_fill() {
+    const DEFAULT_ID: BlockId = BlockId::new(5);
+
+    let mut data = [DEFAULT_ID; CHUNK_SECTION_VOLUME];
+    for (i, voxel) in data.iter_mut().enumerate() {
+        *voxel = BlockId::new((i % 7) as u32);
+    }
+
+    let mut bulk = ChunkSection::new_uninitialized(DEFAULT_ID);
+    bulk.fill_from_slice(&data);
+
+    let mut per_voxel = ChunkSection::new_uninitialized(DEFAULT_ID);
+    for z in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                let i = (x + y * CHUNK_SIZE + z * CHUNK_SIZE * CHUNK_SIZE) as usize;
+                per_voxel.set([x, y, z], data[i]);
+            }
+        }
+    }
+
+    assert!(bulk.is_initialized());
+    assert!(bulk == per_voxel);
+
+    bulk.fill(BlockId::new(42));
+
+    for z in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                per_voxel.set([x, y, z], BlockId::new(42));
+            }
+        }
+    }
+
+    assert!(bulk == per_voxel);
+}
+
+#[test]
+fn chunk_section_fill() {
+    const DEFAULT_ID: BlockId = BlockId::new(5);
+
+    let mut section = ChunkSection::new_uninitialized(DEFAULT_ID);
+    section.fill(BlockId::new(42));
+
+    assert!(section.is_initialized());
+    assert!(section
+        .inner_ref()
+        .unwrap()
+        .iter()
+        .all(|&id| id == BlockId::new(42)));
+
+    // Filling again reuses the storage that's already there.
+    section.set([3i32, 4, 5], BlockId::new(7));
+    section.fill(BlockId::new(9));
+
+    assert!(section
+        .inner_ref()
+        .unwrap()
+        .iter()
+        .all(|&id| id == BlockId::new(9)));
+}
+
+#[test]
+fn chunk_diff_and_apply() {
+    const DEFAULT_ID: BlockId = BlockId::new(1);
+
+    let base = {
+        let mut chunk = Chunk::new(DEFAULT_ID, na::vector![3, -2], -64, 320);
+        chunk.set(Spaces::Cs([4i32, 10, 4]), BlockId::new(9));
+        chunk
+    };
+
+    let mut target = base.clone();
+    target.set(Spaces::Cs([0i32, -64, 0]), BlockId::new(2));
+    target.set(Spaces::Cs([15i32, 319, 15]), BlockId::new(3));
+    target.set(Spaces::Cs([4i32, 10, 4]), DEFAULT_ID);
+
+    let changes = target.diff(&base).unwrap();
+    assert_eq!(changes.len(), 3);
+    assert!(changes.windows(2).all(|w| w[0].0 < w[1].0));
+
+    // Nothing changed, so there's nothing to send.
+    assert!(base.diff(&base).unwrap().is_empty());
+
+    let mut reconstructed = base.clone();
+    reconstructed.apply_delta(&changes);
+    assert_eq!(reconstructed, target);
+
+    // Chunks of different heights can't be diffed.
+    let short = Chunk::new(DEFAULT_ID, na::vector![3, -2], 0, 64);
+    assert!(target.diff(&short).is_none());
+}
+
+#[test]
+fn chunk_content_hash() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash<T: Hash>(t: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        t.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    const AIR: BlockId = BlockId::new(0);
+
+    let a = Chunk::new(AIR, na::vector![0, 0], -64, 320);
+    let b = Chunk::new(AIR, na::vector![12, -5], -64, 320);
+
+    // Same contents at different positions.
+    assert_eq!(a.content_hash(), b.content_hash());
+    assert_ne!(hash(&a), hash(&b));
+
+    // An initialized section filled with air is the same as an uninitialized one.
+    assert_eq!(
+        hash(&ChunkSection::new_initialized(AIR)),
+        hash(&ChunkSection::new_uninitialized(AIR))
+    );
+
+    let mut c = a.clone();
+    c.set(Spaces::Cs([1i32, 0, 1]), BlockId::new(4));
+    assert_ne!(a.content_hash(), c.content_hash());
+}
+
+#[test]
+fn chunk_block_histogram() {
+    const DEFAULT_ID: BlockId = BlockId::new(0);
+    const STONE: BlockId = BlockId::new(1);
+
+    // 2 sections, 8192 voxels in total.
+    let mut chunk = Chunk::new(DEFAULT_ID, na::vector![0, 0], 0, 32);
+
+    // Fill the bottom quarter of the chunk with stone.
+    for x in 0..CHUNK_SIZE {
+        for y in 0..8 {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(Spaces::Cs([x, y, z]), STONE);
+            }
+        }
+    }
+
+    let histogram = chunk.block_histogram();
+
+    assert_eq!(histogram.len(), 2);
+    assert_eq!(histogram[&STONE], 2048);
+    assert_eq!(histogram[&DEFAULT_ID], 6144);
+}
+
+#[test]
+fn chunk_relocation() {
+    const DEFAULT_ID: BlockId = BlockId::new(0);
+
+    let mut chunk = Chunk::new(DEFAULT_ID, na::vector![2, 3], -64, 320);
+    let old_ws = na::vector![2 * CHUNK_SIZE + 5, 70, 3 * CHUNK_SIZE + 9];
+    chunk.set(Spaces::Ws(old_ws), BlockId::new(12));
+
+    let moved = chunk.clone().relocate(na::vector![-1, 5]);
+    assert_eq!(moved.pos(), na::vector![-1, 5]);
+
+    let new_ws = na::vector![-CHUNK_SIZE + 5, 70, 5 * CHUNK_SIZE + 9];
+    assert_eq!(moved.get(Spaces::Ws(new_ws)), Some(&BlockId::new(12)));
+    assert_eq!(moved.get(Spaces::Ws(old_ws)), None);
+    assert_eq!(moved.content_hash(), chunk.content_hash());
+
+    let translated = chunk.translate(na::vector![-3, 2]);
+    assert_eq!(translated, moved);
+}
+
+#[test]
+fn random_chunk_bincode_round_trip() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+
+    // From almost empty to almost entirely random, so we get both long and very short runs.
+    for density in [0.0, 0.001, 0.05, 0.5, 1.0] {
+        let mut chunk = Chunk::new(BlockId::new(0), na::vector![1, -1], -32, 32);
+
+        for x in 0..CHUNK_SIZE {
+            for y in -32..32 {
+                for z in 0..CHUNK_SIZE {
+                    if rng.gen_bool(density) {
+                        let id = BlockId::new(rng.gen_range(0..8));
+                        chunk.set(Spaces::Cs([x, y, z]), id);
+                    }
+                }
+            }
+        }
+
+        let chunk_copy: Chunk = bincode::deserialize(&bincode::serialize(&chunk).unwrap()).unwrap();
+
+        assert_eq!(chunk_copy, chunk);
+    }
+}
+
+#[test]
+fn sparse_chunk_section_serializes_compactly() {
+    let mut cs = ChunkSection::new_initialized(BlockId::new(0));
+    cs.set([8i32, 8, 8], BlockId::new(12));
+
+    let bytes = bincode::serialize(&cs).unwrap();
+
+    // A section storing every voxel individually would take at least 4 bytes per voxel.
+    assert!(bytes.len() < 64);
+    assert_eq!(
+        bincode::deserialize::<ChunkSection>(&bytes)
+            .unwrap()
+            .get([8i32, 8, 8]),
+        Some(&BlockId::new(12))
+    );
+}
+
+#[test]
+fn invalid_voxel_runs_rejected() {
+    let serialize = |runs: &[(u16, BlockId)]| {
+        let mut bytes = bincode::serialize(&(2 + runs.len() as u64)).unwrap();
+        bytes.extend(bincode::serialize(&BlockId::new(0)).unwrap());
+        bytes.extend(bincode::serialize(&true).unwrap());
+        for run in runs {
+            bytes.extend(bincode::serialize(run).unwrap());
+        }
+
+        bytes
+    };
+
+    let full = CHUNK_SECTION_VOLUME as u16;
+
+    assert!(bincode::deserialize::<ChunkSection>(&serialize(&[(full, BlockId::new(1))])).is_ok());
+    assert!(
+        bincode::deserialize::<ChunkSection>(&serialize(&[(full + 1, BlockId::new(1))])).is_err()
+    );
+    assert!(bincode::deserialize::<ChunkSection>(&serialize(&[(0, BlockId::new(1))])).is_err());
+    assert!(
+        bincode::deserialize::<ChunkSection>(&serialize(&[(full - 1, BlockId::new(1))])).is_err()
+    );
+}
@@ -1,22 +1,253 @@
-use std::marker::PhantomData;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
 
 use crate::{BlockId, IVec2};
 
 // TODO: rename this to something with parameters (instead of args) for consistency
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct GenerationArgs {
     pub pos: IVec2,
 }
 
+impl Default for GenerationArgs {
+    fn default() -> Self {
+        Self {
+            pos: IVec2::zeros(),
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct FactoryParameters<'a> {
     pub max_height: i32,
     pub min_height: i32,
     pub default: BlockId,
 
-    // this is here because in the future we're gonna want more complex and lengthy parameters, in which case we don't want to copy them.
-    // it's always a hassle to convert code with plenty of copying into non-copy code after it's written, so we'll write it like this from the start.
-    pub _future_noncopy_params: PhantomData<&'a [u8]>,
-    // TODO: this should have a seed field too for RNG
+    /// Generator specific parameters sent by the client, including the seed generators should use for RNG.
+    /// These are borrowed from the request since they can be arbitrarily large, so we don't want to copy them.
+    pub params: &'a Parameters,
+}
+
+#[derive(te::Error, Debug, PartialEq)]
+pub enum ParameterError {
+    #[error("Parameter '{0}' doesn't exist")]
+    DoesntExist(String),
+    #[error("Parameter '{name}' couldn't be parsed as {expected}")]
+    ParseError {
+        name: String,
+        expected: &'static str,
+    },
+}
+
+/// Value of a generator parameter, see [`Parameters`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ParamValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl From<i64> for ParamValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for ParamValue {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<bool> for ParamValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<String> for ParamValue {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl From<&str> for ParamValue {
+    fn from(value: &str) -> Self {
+        Self::Str(value.to_string())
+    }
+}
+
+/// Named parameters for a generator, sent by the client when adding the generator.
+/// The typed accessors also accept strings that parse as the requested type (e.g., `"42"` for an integer), since
+/// some clients (like config files) only deal in strings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Parameters {
+    /// Seed for the generator's RNG, so the same parameters always generate the same terrain.
+    pub seed: u64,
+    values: HashMap<String, ParamValue>,
+}
+
+impl Parameters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<ParamValue>) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    pub fn with(mut self, name: impl Into<String>, value: impl Into<ParamValue>) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ParamValue, ParameterError> {
+        self.values
+            .get(name)
+            .ok_or_else(|| ParameterError::DoesntExist(name.to_string()))
+    }
+
+    /// Get a parameter as `T`, using `convert` for non-string values and parsing strings with [`str::parse`].
+    fn get_as<T: std::str::FromStr>(
+        &self,
+        name: &str,
+        expected: &'static str,
+        convert: impl FnOnce(&ParamValue) -> Option<T>,
+    ) -> Result<T, ParameterError> {
+        let value = self.get(name)?;
+
+        let converted = match value {
+            ParamValue::Str(string) => string.parse().ok(),
+            value => convert(value),
+        };
+
+        converted.ok_or_else(|| ParameterError::ParseError {
+            name: name.to_string(),
+            expected,
+        })
+    }
+
+    pub fn get_int(&self, name: &str) -> Result<i64, ParameterError> {
+        self.get_as(name, "an integer", |value| match value {
+            ParamValue::Int(int) => Some(*int),
+            _ => None,
+        })
+    }
+
+    /// Get a parameter as a float. Integers are converted to floats.
+    pub fn get_float(&self, name: &str) -> Result<f64, ParameterError> {
+        self.get_as(name, "a float", |value| match value {
+            ParamValue::Float(float) => Some(*float),
+            ParamValue::Int(int) => Some(*int as f64),
+            _ => None,
+        })
+    }
+
+    pub fn get_bool(&self, name: &str) -> Result<bool, ParameterError> {
+        self.get_as(name, "a bool", |value| match value {
+            ParamValue::Bool(bool) => Some(*bool),
+            _ => None,
+        })
+    }
+
+    /// Get a parameter as a string. Only string parameters are accepted.
+    pub fn get_str(&self, name: &str) -> Result<&str, ParameterError> {
+        match self.get(name)? {
+            ParamValue::Str(string) => Ok(string),
+            _ => Err(ParameterError::ParseError {
+                name: name.to_string(),
+                expected: "a string",
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{GenerationArgs, ParameterError, Parameters};
+
+    #[test]
+    fn generation_args_bincode_mirror() {
+        let args = GenerationArgs {
+            pos: na::vector![-6, 2],
+        };
+
+        let args_copy: GenerationArgs =
+            bincode::deserialize(&bincode::serialize(&args).unwrap()).unwrap();
+
+        assert_eq!(args_copy, args);
+    }
+
+    #[test]
+    fn generation_args_equality() {
+        let a = GenerationArgs {
+            pos: na::vector![4, 4],
+        };
+        let b = GenerationArgs {
+            pos: na::vector![4, 4],
+        };
+        let c = GenerationArgs {
+            pos: na::vector![4, 5],
+        };
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        // Equal requests should be deduplicated.
+        let set = [a, b, c].into_iter().collect::<HashSet<_>>();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn typed_parameters() {
+        let params = Parameters::new()
+            .with_seed(1234)
+            .with("octaves", 4i64)
+            .with("scale", 0.5)
+            .with("caves", true)
+            .with("biome", "plains")
+            .with("height", "64");
+
+        assert_eq!(params.get_int("octaves"), Ok(4));
+        assert_eq!(params.get_float("scale"), Ok(0.5));
+        assert_eq!(params.get_float("octaves"), Ok(4.0));
+        assert_eq!(params.get_bool("caves"), Ok(true));
+        assert_eq!(params.get_str("biome"), Ok("plains"));
+        assert_eq!(params.get_int("height"), Ok(64));
+
+        assert_eq!(
+            params.get_int("missing"),
+            Err(ParameterError::DoesntExist("missing".to_string()))
+        );
+        assert!(matches!(
+            params.get_int("biome"),
+            Err(ParameterError::ParseError { .. })
+        ));
+        assert!(matches!(
+            params.get_bool("octaves"),
+            Err(ParameterError::ParseError { .. })
+        ));
+
+        let params_copy: Parameters =
+            bincode::deserialize(&bincode::serialize(&params).unwrap()).unwrap();
+        assert_eq!(params_copy, params);
+    }
 }
@@ -10,7 +10,15 @@ extern crate thiserror as te;
 extern crate volume as vol;
 
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+
+/// Version of the framing protocol, sent as the first byte of every packet header.
+/// This must be bumped whenever the framing or the encoding of a packet changes, so that peers speaking an old
+/// protocol are rejected instead of reading garbage. The server and the plugin natives both use this constant, but
+/// the plugin's Kotlin side has its own copy that must be kept in sync.
+///
+/// Version 3 run-length encodes the voxels of initialized chunk sections.
+pub const PROTOCOL_VERSION: u8 = 3;
 
 macro_rules! impl_display_debug {
     ($t:ty) => {
@@ -53,10 +61,96 @@ pub struct RequestId(pub u32);
 impl_display_debug!(RequestId);
 impl_from_u32_id!(RequestId);
 
+/// Identifies a single connection to the server. Peers can reconnect from the same address (or have their port
+/// reused by a NAT), so the ID also contains a sequence number that's unique for every connection the server accepts.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct ConnectionId(pub SocketAddrV4);
+pub struct ConnectionId {
+    addr: SocketAddr,
+    seq: u64,
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.addr, self.seq)
+    }
+}
+
+impl std::fmt::Debug for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl ConnectionId {
+    #[inline]
+    pub fn new(addr: SocketAddr, seq: u64) -> Self {
+        Self { addr, seq }
+    }
 
-impl_display_debug!(ConnectionId);
+    /// The address of the peer.
+    #[inline]
+    pub fn addr(self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The connection's sequence number.
+    #[inline]
+    pub fn seq(self) -> u64 {
+        self.seq
+    }
+
+    /// Display this ID with the host's address masked out except for the last octet (e.g., `x.x.x.12:4432#3`),
+    /// or the last segment for IPv6 (e.g., `[x:x:x:x:x:x:x:1a2b]:4432#3`), so it can be logged without exposing
+    /// the client's address.
+    #[inline]
+    pub fn redacted(self) -> ConnectionIdDisplay {
+        self.display(true)
+    }
+
+    /// Display this ID, redacting it if `redact` is true.
+    #[inline]
+    pub fn display(self, redact: bool) -> ConnectionIdDisplay {
+        ConnectionIdDisplay { id: self, redact }
+    }
+}
+
+/// Displays a [`ConnectionId`], optionally with the host's address redacted. See [`ConnectionId::redacted`].
+#[derive(Copy, Clone)]
+pub struct ConnectionIdDisplay {
+    id: ConnectionId,
+    redact: bool,
+}
+
+impl std::fmt::Display for ConnectionIdDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.redact {
+            match self.id.addr() {
+                SocketAddr::V4(addr) => write!(
+                    f,
+                    "x.x.x.{}:{}#{}",
+                    addr.ip().octets()[3],
+                    addr.port(),
+                    self.id.seq()
+                ),
+                SocketAddr::V6(addr) => write!(
+                    f,
+                    "[x:x:x:x:x:x:x:{:x}]:{}#{}",
+                    addr.ip().segments()[7],
+                    addr.port(),
+                    self.id.seq()
+                ),
+            }
+        } else {
+            write!(f, "{}", self.id)
+        }
+    }
+}
+
+impl std::fmt::Debug for ConnectionIdDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct GeneratorId(pub u32);
@@ -64,7 +158,7 @@ pub struct GeneratorId(pub u32);
 impl_display_debug!(GeneratorId);
 impl_from_u32_id!(GeneratorId);
 
-#[derive(Default, Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub struct BlockId(pub u32);
 
 impl BlockId {
@@ -84,3 +178,35 @@ impl From<BlockId> for u32 {
         val.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ConnectionId;
+
+    #[test]
+    fn redacted_connection_id() {
+        let id = ConnectionId::new("192.168.1.12:4432".parse().unwrap(), 3);
+
+        assert_eq!(id.redacted().to_string(), "x.x.x.12:4432#3");
+        assert_eq!(id.display(true).to_string(), "x.x.x.12:4432#3");
+        assert_eq!(id.display(false).to_string(), "192.168.1.12:4432#3");
+    }
+
+    #[test]
+    fn redacted_ipv6_connection_id() {
+        let id = ConnectionId::new("[2001:db8::1a2b]:4432".parse().unwrap(), 3);
+
+        assert_eq!(id.redacted().to_string(), "[x:x:x:x:x:x:x:1a2b]:4432#3");
+        assert_eq!(id.display(false).to_string(), "[2001:db8::1a2b]:4432#3");
+    }
+
+    #[test]
+    fn connection_ids_from_same_address() {
+        let addr = "192.168.1.12:4432".parse().unwrap();
+        let first = ConnectionId::new(addr, 0);
+        let second = ConnectionId::new(addr, 1);
+
+        assert_ne!(first, second);
+        assert_eq!(first.addr(), second.addr());
+    }
+}
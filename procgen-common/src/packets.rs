@@ -1,10 +1,10 @@
 use std::{
     io::{self, Read},
-    marker::PhantomData,
     mem::size_of,
+    time::Duration,
 };
 
-use crate::generation::{FactoryParameters, GenerationArgs};
+use crate::generation::{FactoryParameters, GenerationArgs, ParamValue, Parameters};
 
 use crate::{BlockId, Chunk, GeneratorId, RequestId};
 
@@ -46,15 +46,26 @@ pub struct PacketBuffer {
 impl PacketBuffer {
     pub fn from_reader<R: Read>(reader: &mut R) -> Result<Self, PacketBufferError> {
         let mut buf = Vec::<u8>::new();
+        reader.read_to_end(&mut buf)?;
 
+        Self::from_vec(buf)
+    }
+
+    /// Create a buffer from raw packet data (the ID followed by the packet's body) without copying it.
+    pub fn from_vec(buf: Vec<u8>) -> Result<Self, PacketBufferError> {
         // We only allow valid packet data, so there must be enough bytes to produce an ID.
-        if reader.read_to_end(&mut buf)? < size_of::<u16>() {
+        if buf.len() < size_of::<u16>() {
             return Err(PacketBufferError::PacketTooShort);
         }
 
         Ok(Self { inner: buf })
     }
 
+    /// Take the raw packet data out of this buffer without copying it.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.inner
+    }
+
     pub fn id(&self) -> u16 {
         u16::from_be_bytes(self.inner[..size_of::<u16>()].try_into().unwrap())
     }
@@ -89,7 +100,7 @@ impl AsRef<[u8]> for PacketBuffer {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct GenerateChunk {
     pub request_id: RequestId,
     pub generator_id: GeneratorId,
@@ -106,7 +117,7 @@ impl Packet for GenerateChunk {
     const ID: u16 = 0;
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ReplyChunk {
     pub request_id: RequestId,
     pub chunk: Chunk,
@@ -116,23 +127,102 @@ impl Packet for ReplyChunk {
     const ID: u16 = 1;
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Like [`ReplyChunk`], but only contains the voxels that differ from a base chunk the client already has.
+/// Sent in reply to a [`GenerateChunkDelta`], the client reconstructs the chunk by applying the changes to its base
+/// chunk with [`Chunk::apply_delta`].
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReplyChunkDelta {
+    pub request_id: RequestId,
+    pub pos: na::Vector2<i32>,
+    pub changes: Vec<(u32, BlockId)>,
+}
+
+impl ReplyChunkDelta {
+    /// Create a reply containing the changes needed to turn `base` into `chunk`.
+    /// Returns `None` if the chunks have different heights (see [`Chunk::diff`]).
+    pub fn new(request_id: RequestId, chunk: &Chunk, base: &Chunk) -> Option<Self> {
+        Some(Self {
+            request_id,
+            pos: chunk.pos(),
+            changes: chunk.diff(base)?,
+        })
+    }
+}
+
+impl Packet for ReplyChunkDelta {
+    const ID: u16 = 5;
+}
+
+/// Like [`GenerateChunk`], but the server replies with a [`ReplyChunkDelta`] against the chunk that
+/// `base_generator_id` generates at the same position. Useful when the client already has that chunk, such as when
+/// regenerating a chunk after adding a generator with tweaked parameters.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GenerateChunkDelta {
+    pub request_id: RequestId,
+    pub generator_id: GeneratorId,
+    pub base_generator_id: GeneratorId,
+    pub pos: na::Vector2<i32>,
+}
+
+impl Packet for GenerateChunkDelta {
+    const ID: u16 = 13;
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct AddGenerator {
     pub request_id: RequestId,
     pub name: String,
     pub min_height: i32,
     pub max_height: i32,
     pub default_id: BlockId,
+    /// Parameters for the generator's factory, see [`FactoryParameters::params`].
+    pub params: Parameters,
 }
 
 impl AddGenerator {
+    /// Start building an [`AddGenerator`] request. Fields that aren't set are left at their defaults: request ID 0,
+    /// a region from y=-64 to y=320 (like vanilla Minecraft), and block ID 0 as the default block.
+    ///
+    /// ```
+    /// use procgen_common::packets::AddGenerator;
+    ///
+    /// let packet = AddGenerator::builder()
+    ///     .request_id(5.into())
+    ///     .name("GENERATOR")
+    ///     .region(0, 256)
+    ///     .param("octaves", 4i64)
+    ///     .build();
+    ///
+    /// assert_eq!(packet.name, "GENERATOR");
+    /// assert_eq!(packet.params.get_int("octaves"), Ok(4));
+    /// assert_eq!((packet.min_height, packet.max_height), (0, 256));
+    /// assert!(packet.validate().is_ok());
+    /// ```
+    pub fn builder() -> AddGeneratorBuilder {
+        AddGeneratorBuilder::default()
+    }
+
+    /// Check that the requested generator would generate chunks with a positive height.
+    pub fn validate(&self) -> Result<(), ProtocolErrorKind> {
+        if self.max_height <= self.min_height {
+            return Err(ProtocolErrorKind::InvalidRegion {
+                request_id: self.request_id,
+                details: format!(
+                    "max height ({}) must be greater than min height ({})",
+                    self.max_height, self.min_height
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
     pub fn factory_params(&self) -> FactoryParameters<'_> {
         FactoryParameters {
             min_height: self.min_height,
             max_height: self.max_height,
             default: self.default_id,
-
-            _future_noncopy_params: PhantomData,
+            params: &self.params,
         }
     }
 }
@@ -141,7 +231,79 @@ impl Packet for AddGenerator {
     const ID: u16 = 2;
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Builder for [`AddGenerator`], see [`AddGenerator::builder`].
+#[derive(Debug, Clone)]
+pub struct AddGeneratorBuilder {
+    request_id: RequestId,
+    name: String,
+    min_height: i32,
+    max_height: i32,
+    default_id: BlockId,
+    params: Parameters,
+}
+
+impl Default for AddGeneratorBuilder {
+    fn default() -> Self {
+        Self {
+            request_id: RequestId(0),
+            name: String::new(),
+            min_height: -64,
+            max_height: 320,
+            default_id: BlockId::default(),
+            params: Parameters::default(),
+        }
+    }
+}
+
+impl AddGeneratorBuilder {
+    pub fn request_id(mut self, request_id: RequestId) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set the heights (in blocks) the generator's chunks span.
+    pub fn region(mut self, min_height: i32, max_height: i32) -> Self {
+        self.min_height = min_height;
+        self.max_height = max_height;
+        self
+    }
+
+    pub fn default_id(mut self, default_id: BlockId) -> Self {
+        self.default_id = default_id;
+        self
+    }
+
+    /// Set the seed for the generator's RNG.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.params.seed = seed;
+        self
+    }
+
+    /// Set a parameter for the generator's factory.
+    pub fn param(mut self, name: impl Into<String>, value: impl Into<ParamValue>) -> Self {
+        self.params.set(name, value);
+        self
+    }
+
+    /// Build the request. This doesn't validate it, see [`AddGenerator::validate`].
+    pub fn build(self) -> AddGenerator {
+        AddGenerator {
+            request_id: self.request_id,
+            name: self.name,
+            min_height: self.min_height,
+            max_height: self.max_height,
+            default_id: self.default_id,
+            params: self.params,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ConfirmGeneratorAddition {
     pub request_id: RequestId,
     pub generator_id: GeneratorId,
@@ -160,7 +322,84 @@ impl Packet for ConfirmGeneratorAddition {
     const ID: u16 = 3;
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Cheap liveness probe, the server answers immediately with a [`HealthOk`] echoing the nonce.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HealthCheck {
+    pub nonce: u64,
+}
+
+impl Packet for HealthCheck {
+    const ID: u16 = 6;
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HealthOk {
+    pub nonce: u64,
+    /// How long the server has been running for.
+    pub uptime_ms: u64,
+    /// How many chunk requests are currently being generated.
+    pub active_requests: u32,
+}
+
+impl Packet for HealthOk {
+    const ID: u16 = 7;
+}
+
+/// Ask the server to cancel a chunk request it hasn't replied to yet. The server answers with an [`AckRequest`] if
+/// the request was cancelled, after which it won't send a reply for it.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CancelRequest {
+    pub request_id: RequestId,
+}
+
+impl Packet for CancelRequest {
+    const ID: u16 = 8;
+}
+
+/// Acknowledges a request that doesn't otherwise get a reply, such as [`CancelRequest`].
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AckRequest {
+    pub request_id: RequestId,
+    pub info: String,
+}
+
+impl Packet for AckRequest {
+    const ID: u16 = 9;
+}
+
+/// Ask the server which generators it has, answered with a [`ListGenerators`].
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RequestGenerators {
+    pub request_id: RequestId,
+}
+
+impl Packet for RequestGenerators {
+    const ID: u16 = 10;
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ListGenerators {
+    pub request_id: RequestId,
+    /// Names of the generators that can be added with [`AddGenerator`], sorted alphabetically.
+    pub generators: Vec<String>,
+}
+
+impl Packet for ListGenerators {
+    const ID: u16 = 11;
+}
+
+/// Sent periodically by the server to check that the connection is still alive. Clients must echo it back
+/// unchanged, otherwise they're disconnected once enough heartbeats go unanswered.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Heartbeat {
+    pub nonce: u64,
+}
+
+impl Packet for Heartbeat {
+    const ID: u16 = 12;
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 #[non_exhaustive]
 pub enum ProtocolErrorKind {
     Other {
@@ -178,10 +417,74 @@ pub enum ProtocolErrorKind {
     Terminated {
         details: String,
     },
+    InvalidRegion {
+        request_id: RequestId,
+        details: String,
+    },
+    ServerBusy {
+        request_id: RequestId,
+        retry_after_ms: u64,
+    },
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    /// The server has no generator with the name the client asked for in an [`AddGenerator`].
+    UnknownGenerator {
+        request_id: RequestId,
+        name: String,
+    },
+}
+
+impl ProtocolErrorKind {
+    /// The ID of the request this error is about, if it's about a specific request.
+    pub fn request_id(&self) -> Option<RequestId> {
+        match self {
+            Self::GeneratorNotFound { request_id, .. }
+            | Self::ChunkGenerationFailure { request_id, .. }
+            | Self::InvalidRegion { request_id, .. }
+            | Self::ServerBusy { request_id, .. }
+            | Self::UnknownGenerator { request_id, .. } => Some(*request_id),
+            _ => None,
+        }
+    }
+
+    /// Human readable description of this error, for logging.
+    pub fn message(&self) -> String {
+        match self {
+            Self::Other { details } => details.clone(),
+            Self::GeneratorNotFound { generator_id, .. } => {
+                format!("generator {generator_id} was not found")
+            }
+            Self::ChunkGenerationFailure {
+                generator_id,
+                details,
+                ..
+            } => format!("generator {generator_id} failed to generate chunk: {details}"),
+            Self::Terminated { details } => format!("connection terminated: {details}"),
+            Self::InvalidRegion { details, .. } => format!("invalid region: {details}"),
+            Self::ServerBusy { retry_after_ms, .. } => {
+                format!("server is busy, retry after {retry_after_ms}ms")
+            }
+            Self::RateLimited { retry_after_ms } => {
+                format!("rate limited, retry after {retry_after_ms}ms")
+            }
+            Self::UnknownGenerator { name, .. } => format!("no generator named '{name}'"),
+        }
+    }
+
+    /// How long the client should wait before retrying, if this error is one it can back off from.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::ServerBusy { retry_after_ms, .. } | Self::RateLimited { retry_after_ms } => {
+                Some(Duration::from_millis(*retry_after_ms))
+            }
+            _ => None,
+        }
+    }
 }
 
 // TODO: finish implementing this
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ProtocolError {
     pub kind: ProtocolErrorKind,
     pub fatal: bool,
@@ -195,8 +498,243 @@ impl ProtocolError {
     pub fn fatal(kind: ProtocolErrorKind) -> Self {
         Self { kind, fatal: true }
     }
+
+    /// The server is too busy to handle the request with ID `request_id`, which can be retried after `retry_after`.
+    pub fn server_busy(request_id: RequestId, retry_after: Duration) -> Self {
+        Self::gentle(ProtocolErrorKind::ServerBusy {
+            request_id,
+            retry_after_ms: retry_after.as_millis() as u64,
+        })
+    }
+
+    /// The client is sending requests too quickly and should wait `retry_after` before sending more.
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self::gentle(ProtocolErrorKind::RateLimited {
+            retry_after_ms: retry_after.as_millis() as u64,
+        })
+    }
 }
 
 impl Packet for ProtocolError {
     const ID: u16 = 4;
 }
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = if self.fatal { "fatal" } else { "gentle" };
+
+        match self.kind.request_id() {
+            Some(request_id) => write!(
+                f,
+                "[{severity}] request {request_id}: {}",
+                self.kind.message()
+            ),
+            None => write!(f, "[{severity}] {}", self.kind.message()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use vol::Volume;
+
+    use super::*;
+
+    #[test]
+    fn packet_buffer_vec_round_trip() {
+        let bytes = vec![0, 1, 42, 43, 44];
+        let buf = PacketBuffer::from_vec(bytes.clone()).unwrap();
+
+        assert_eq!(buf.id(), 1);
+        assert_eq!(buf.into_inner(), bytes);
+
+        assert!(matches!(
+            PacketBuffer::from_vec(vec![7]),
+            Err(PacketBufferError::PacketTooShort)
+        ));
+    }
+
+    #[test]
+    fn packet_ids_unique() {
+        // Every packet must be listed here, dispatch silently breaks if two packets share an ID.
+        let ids = [
+            GenerateChunk::ID,
+            ReplyChunk::ID,
+            AddGenerator::ID,
+            ConfirmGeneratorAddition::ID,
+            ProtocolError::ID,
+            ReplyChunkDelta::ID,
+            HealthCheck::ID,
+            HealthOk::ID,
+            CancelRequest::ID,
+            AckRequest::ID,
+            RequestGenerators::ID,
+            ListGenerators::ID,
+            Heartbeat::ID,
+            GenerateChunkDelta::ID,
+        ];
+
+        let unique = ids.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), ids.len(), "duplicate packet ID in {ids:?}");
+    }
+
+    fn assert_round_trip<P: Packet + PartialEq + std::fmt::Debug>(packet: P) {
+        let buf = packet.to_bincode().unwrap();
+        assert_eq!(buf.id(), P::ID);
+        assert_eq!(P::from_bincode(&buf).unwrap(), packet);
+    }
+
+    #[test]
+    fn every_packet_round_trips() {
+        let mut chunk = Chunk::new(BlockId::new(3), na::vector![-4, 9], -64, 320);
+        chunk.set(crate::Spaces::Cs([1i32, 20, 3]), BlockId::new(7));
+
+        assert_round_trip(GenerateChunk {
+            request_id: 1.into(),
+            generator_id: 2.into(),
+            pos: na::vector![-3, 5],
+        });
+        assert_round_trip(ReplyChunk {
+            request_id: 3.into(),
+            chunk: chunk.clone(),
+        });
+        assert_round_trip(AddGenerator {
+            request_id: 4.into(),
+            name: "GENERATOR".to_string(),
+            min_height: -64,
+            max_height: 320,
+            default_id: BlockId::new(1),
+            params: Parameters::new().with("scale", 0.5).with("name", "x"),
+        });
+        assert_round_trip(ConfirmGeneratorAddition::new(5.into(), 6.into()));
+        assert_round_trip(
+            ReplyChunkDelta::new(
+                7.into(),
+                &chunk,
+                &Chunk::new(BlockId::new(3), na::vector![-4, 9], -64, 320),
+            )
+            .unwrap(),
+        );
+        assert_round_trip(HealthCheck { nonce: 14 });
+        assert_round_trip(HealthOk {
+            nonce: 15,
+            uptime_ms: 16,
+            active_requests: 17,
+        });
+        assert_round_trip(CancelRequest {
+            request_id: 18.into(),
+        });
+        assert_round_trip(AckRequest {
+            request_id: 19.into(),
+            info: "cancelled".to_string(),
+        });
+        assert_round_trip(RequestGenerators {
+            request_id: 20.into(),
+        });
+        assert_round_trip(ListGenerators {
+            request_id: 21.into(),
+            generators: vec!["A".to_string(), "B".to_string()],
+        });
+        assert_round_trip(Heartbeat { nonce: 22 });
+        assert_round_trip(GenerateChunkDelta {
+            request_id: 23.into(),
+            generator_id: 24.into(),
+            base_generator_id: 25.into(),
+            pos: na::vector![7, -1],
+        });
+
+        let kinds = [
+            ProtocolErrorKind::Other {
+                details: "other".to_string(),
+            },
+            ProtocolErrorKind::GeneratorNotFound {
+                generator_id: 8.into(),
+                request_id: 9.into(),
+            },
+            ProtocolErrorKind::ChunkGenerationFailure {
+                generator_id: 10.into(),
+                request_id: 11.into(),
+                details: "failure".to_string(),
+            },
+            ProtocolErrorKind::Terminated {
+                details: "terminated".to_string(),
+            },
+            ProtocolErrorKind::InvalidRegion {
+                request_id: 12.into(),
+                details: "invalid".to_string(),
+            },
+            ProtocolErrorKind::ServerBusy {
+                request_id: 13.into(),
+                retry_after_ms: 250,
+            },
+            ProtocolErrorKind::RateLimited {
+                retry_after_ms: 1000,
+            },
+            ProtocolErrorKind::UnknownGenerator {
+                request_id: 14.into(),
+                name: "GENERATOR".to_string(),
+            },
+        ];
+
+        for kind in kinds {
+            assert_round_trip(ProtocolError::gentle(kind));
+        }
+        assert_round_trip(ProtocolError::fatal(ProtocolErrorKind::Other {
+            details: "fatal".to_string(),
+        }));
+    }
+
+    #[test]
+    fn protocol_error_display() {
+        let error = ProtocolError::fatal(ProtocolErrorKind::GeneratorNotFound {
+            generator_id: 8.into(),
+            request_id: 9.into(),
+        });
+        assert_eq!(
+            error.to_string(),
+            "[fatal] request 9: generator 8 was not found"
+        );
+
+        let error = ProtocolError::rate_limited(Duration::from_millis(1500));
+        assert_eq!(
+            error.to_string(),
+            "[gentle] rate limited, retry after 1500ms"
+        );
+    }
+
+    #[test]
+    fn retry_hints() {
+        let busy = ProtocolError::server_busy(14.into(), Duration::from_millis(250));
+        let limited = ProtocolError::rate_limited(Duration::from_secs(2));
+
+        for error in [busy, limited] {
+            assert!(!error.fatal);
+
+            let buf = error.to_bincode().unwrap();
+            let decoded = ProtocolError::from_bincode(&buf).unwrap();
+            assert_eq!(decoded.kind.retry_after(), error.kind.retry_after());
+        }
+
+        assert_eq!(
+            ProtocolError::server_busy(14.into(), Duration::from_millis(250))
+                .kind
+                .retry_after(),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            ProtocolError::rate_limited(Duration::from_secs(2))
+                .kind
+                .retry_after(),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            ProtocolErrorKind::Other {
+                details: "no hint".to_string()
+            }
+            .retry_after(),
+            None
+        );
+    }
+}
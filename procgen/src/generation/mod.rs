@@ -1,5 +1,19 @@
 use common::generation::{FactoryParameters, GenerationArgs};
 use common::Chunk;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// Create an RNG for generating the chunk requested with `args`, seeded with `seed` (usually
+/// [`common::generation::Parameters::seed`]) mixed with the chunk's position.
+/// Every chunk gets its own RNG, so a chunk comes out the same no matter which order chunks are generated in.
+/// The RNG is a fixed algorithm (unlike [`rand::rngs::StdRng`]), so seeds generate the same terrain across versions.
+pub fn chunk_rng(seed: u64, args: &GenerationArgs) -> ChaCha8Rng {
+    // Multiply the coordinates by large odd constants so that neighbouring chunks get very different seeds.
+    let x = (args.pos.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let z = (args.pos.y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+
+    ChaCha8Rng::seed_from_u64(seed ^ x ^ z.rotate_left(32))
+}
 
 pub trait DynGeneratorFactory: Send + Sync {
     fn create(&self, params: FactoryParameters<'_>) -> Box<dyn DynChunkGenerator>;
@@ -28,6 +42,9 @@ pub trait ChunkGenerator: Send + Sync + DynChunkGenerator {
     /// Implementors can honestly do whatever they feel like here with the chunk, this is THE terrain generation function.
     /// Erroring as an implementor will (probably) just result in the error getting logged and the server continuing, so any error handling must be done
     /// manually within the function.
+    ///
+    /// Generation is never interrupted, not even when the client cancels the request (the chunk is just thrown away
+    /// afterwards), so slow generators hold on to a worker thread until they return.
     fn generate(&self, args: &GenerationArgs) -> anyhow::Result<Chunk>;
 
     fn factory() -> Self::Factory;
@@ -0,0 +1,34 @@
+//! A server that generates Minecraft chunks for clients (like the plugin) on request, and an async [`Client`] for
+//! talking to it from Rust.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), procgen::ClientError> {
+//! use procgen::{BlockId, Client, Parameters};
+//!
+//! let mut client = Client::connect("127.0.0.1:44332".parse().unwrap()).await?;
+//!
+//! let generator_id = client
+//!     .add_generator("BIG_FART", -64, 320, BlockId::new(0), Parameters::new())
+//!     .await?;
+//! let chunk = client.generate_chunk(generator_id, nalgebra::vector![0, 0]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+extern crate downcast_rs as dc;
+extern crate nalgebra as na;
+extern crate procgen_common as common;
+extern crate thiserror as te;
+extern crate volume as vol;
+
+pub mod generation;
+#[allow(dead_code)]
+pub mod runtime;
+mod util;
+
+pub use common::{generation::Parameters, BlockId, Chunk, GeneratorId};
+pub use runtime::client::{Client, ClientError};
+pub use runtime::net::{
+    packets::{HealthOk, ProtocolError, ProtocolErrorKind},
+    CompressionAlgo, CompressorError,
+};
@@ -4,22 +4,20 @@ use common::{
     BlockId, Chunk, Spaces,
 };
 use flate2::Compression;
-use generation::{ChunkGenerator, GeneratorFactory};
+use procgen::{
+    generation::{chunk_rng, ChunkGenerator, GeneratorFactory},
+    runtime::{
+        net::{packets, CompressionAlgo, Compressor},
+        server::{Server, ServerParams},
+    },
+};
+use rand::Rng;
 use vol::Volume;
 
-use crate::runtime::{net::Compressor, server::Server};
-
-extern crate downcast_rs as dc;
 extern crate nalgebra as na;
 extern crate procgen_common as common;
-extern crate thiserror as te;
 extern crate volume as vol;
 
-mod generation;
-#[allow(dead_code)]
-mod runtime;
-mod util;
-
 struct MockGenFactory;
 
 impl GeneratorFactory for MockGenFactory {
@@ -30,6 +28,7 @@ impl GeneratorFactory for MockGenFactory {
             min_height: params.min_height,
             max_height: params.max_height,
             default_id: params.default,
+            seed: params.params.seed,
             noise: FastNoise::new(),
         }
     }
@@ -39,6 +38,7 @@ struct MockGenerator {
     min_height: i32,
     max_height: i32,
     default_id: BlockId,
+    seed: u64,
     noise: FastNoise,
 }
 
@@ -49,6 +49,7 @@ impl ChunkGenerator for MockGenerator {
 
     fn generate(&self, args: &GenerationArgs) -> anyhow::Result<Chunk> {
         let mut chunk = Chunk::new(self.default_id, args.pos, self.min_height, self.max_height);
+        let mut rng = chunk_rng(self.seed, args);
 
         for x in 0..16 {
             for z in 0..16 {
@@ -65,7 +66,9 @@ impl ChunkGenerator for MockGenerator {
 
                 for y in self.min_height..(height.floor() as i32) {
                     // println!("{y}");
-                    chunk.set(Spaces::Cs([x, y, z]), 1.into());
+                    // Sprinkle in some ore so the terrain isn't completely uniform.
+                    let id = if rng.gen_ratio(1, 64) { 2 } else { 1 };
+                    chunk.set(Spaces::Cs([x, y, z]), id.into());
                 }
             }
         }
@@ -80,7 +83,7 @@ impl ChunkGenerator for MockGenerator {
 
 #[tokio::main]
 async fn main() {
-    use runtime::net::packets::Packet;
+    use packets::Packet;
 
     env_logger::init();
 
@@ -88,12 +91,12 @@ async fn main() {
     chunk.set(Spaces::Cs([8i32, -60, 4]), 42.into());
     chunk.set(Spaces::Cs([1i32, 310, 2]), 42.into());
 
-    let packet = runtime::net::packets::ReplyChunk {
+    let packet = packets::ReplyChunk {
         request_id: 400.into(),
         chunk,
     };
 
-    let compressor = Compressor::new(Compression::best());
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()));
 
     let mut compressed_buf = Vec::new();
     compressor
@@ -110,18 +113,20 @@ async fn main() {
     //         .unwrap()
     // );
 
-    let mut server = Server::new(runtime::server::ServerParams {
-        addr: "0.0.0.0:44332".parse().unwrap(),
-        compression: Compression::best(),
-        coarsening: 50,
+    let mut server = Server::new(ServerParams {
+        addrs: vec!["0.0.0.0:44332".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        redact_addresses: false,
+        ..Default::default()
     });
-    server.add_generator::<MockGenerator>().await;
+    server.add_generator::<MockGenerator>().await.unwrap();
 
     server.run().await;
 
-    loop {
-        std::hint::spin_loop()
-    }
+    tokio::signal::ctrl_c().await.unwrap();
+    log::info!("shutting down");
+
+    server.stop().await.unwrap();
 }
 
 #[cfg(test)]
@@ -0,0 +1,198 @@
+//! An async client for talking to a generator server, so Rust code can request chunks without reimplementing the
+//! framing. The client sends one request at a time and waits for the server's answer before returning.
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::net::TcpStream;
+
+use common::{generation::Parameters, BlockId, Chunk, GeneratorId, RequestId};
+
+use super::net::{
+    packets::{
+        AddGenerator, ConfirmGeneratorAddition, GenerateChunk, GenerateChunkDelta, HealthCheck,
+        HealthOk, Heartbeat, ListGenerators, Packet, PacketBufferError, ProtocolError, ReplyChunk,
+        ReplyChunkDelta, RequestGenerators,
+    },
+    CompressionAlgo, Compressor, CompressorError,
+};
+
+#[derive(Debug, te::Error)]
+pub enum ClientError {
+    #[error("Error when reading or writing packet: {0}")]
+    Compressor(#[from] CompressorError),
+    #[error("Invalid packet: {0}")]
+    InvalidPacket(#[from] PacketBufferError),
+    #[error("Server responded with an error: {0}")]
+    Protocol(ProtocolError),
+    #[error("Server responded with unexpected packet (ID {0})")]
+    UnexpectedPacket(u16),
+    #[error("Couldn't connect to server: {0}")]
+    Connect(#[from] std::io::Error),
+    #[error("Server didn't respond within {0:?}")]
+    TimedOut(Duration),
+}
+
+pub struct Client {
+    stream: TcpStream,
+    compressor: Compressor,
+    next_request_id: u32,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    /// Connect to the server at `addr`. Packets are compressed with zlib, use [`Client::with_compression`] to change this.
+    pub async fn connect(addr: SocketAddr) -> Result<Self, ClientError> {
+        Ok(Self {
+            stream: TcpStream::connect(addr).await?,
+            compressor: Compressor::new(CompressionAlgo::default()),
+            next_request_id: 0,
+            timeout: None,
+        })
+    }
+
+    pub fn with_compression(mut self, algorithm: CompressionAlgo) -> Self {
+        self.compressor = Compressor::new(algorithm);
+        self
+    }
+
+    /// Give up on requests the server hasn't answered within `timeout`, failing them with [`ClientError::TimedOut`].
+    /// By default the client waits for answers forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Add a generator to the server, returning the ID to request chunks from it with.
+    pub async fn add_generator(
+        &mut self,
+        name: &str,
+        min_height: i32,
+        max_height: i32,
+        default_id: BlockId,
+        params: Parameters,
+    ) -> Result<GeneratorId, ClientError> {
+        let request_id = self.request_id();
+        self.send(&AddGenerator {
+            request_id,
+            name: name.to_string(),
+            min_height,
+            max_height,
+            default_id,
+            params,
+        })
+        .await?;
+
+        Ok(self
+            .receive::<ConfirmGeneratorAddition>(request_id)
+            .await?
+            .generator_id)
+    }
+
+    /// Generate the chunk at `pos` (in chunk coordinates) with the generator `generator_id`.
+    pub async fn generate_chunk(
+        &mut self,
+        generator_id: GeneratorId,
+        pos: na::Vector2<i32>,
+    ) -> Result<Chunk, ClientError> {
+        let request_id = self.request_id();
+        self.send(&GenerateChunk {
+            request_id,
+            generator_id,
+            pos,
+        })
+        .await?;
+
+        Ok(self.receive::<ReplyChunk>(request_id).await?.chunk)
+    }
+
+    /// Generate the chunk at `pos` with the generator `generator_id`, but only get the voxels that differ from the chunk
+    /// that `base_generator_id` generates there. Apply them to that chunk with [`Chunk::apply_delta`] to get the result.
+    pub async fn generate_chunk_delta(
+        &mut self,
+        generator_id: GeneratorId,
+        base_generator_id: GeneratorId,
+        pos: na::Vector2<i32>,
+    ) -> Result<Vec<(u32, BlockId)>, ClientError> {
+        let request_id = self.request_id();
+        self.send(&GenerateChunkDelta {
+            request_id,
+            generator_id,
+            base_generator_id,
+            pos,
+        })
+        .await?;
+
+        Ok(self.receive::<ReplyChunkDelta>(request_id).await?.changes)
+    }
+
+    /// Get the names of the generators that can be added to the server.
+    pub async fn generators(&mut self) -> Result<Vec<String>, ClientError> {
+        let request_id = self.request_id();
+        self.send(&RequestGenerators { request_id }).await?;
+
+        Ok(self.receive::<ListGenerators>(request_id).await?.generators)
+    }
+
+    pub async fn health_check(&mut self) -> Result<HealthOk, ClientError> {
+        let request_id = self.request_id();
+        self.send(&HealthCheck {
+            nonce: request_id.0.into(),
+        })
+        .await?;
+
+        self.receive::<HealthOk>(request_id).await
+    }
+
+    fn request_id(&mut self) -> RequestId {
+        let id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        id.into()
+    }
+
+    async fn send<P: Packet>(&mut self, packet: &P) -> Result<(), ClientError> {
+        self.compressor
+            .write(&packet.to_bincode()?, &mut self.stream)
+            .await?;
+        Ok(())
+    }
+
+    /// Wait for the server to send a `P` in response to the request `request_id`. Errors sent by the server about
+    /// this request (or the connection as a whole) are returned as [`ClientError::Protocol`], errors about other
+    /// requests (e.g., ones that timed out earlier) are ignored. Heartbeats are echoed while waiting.
+    async fn receive<P: Packet>(&mut self, request_id: RequestId) -> Result<P, ClientError> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receive_inner(request_id))
+                .await
+                .map_err(|_| ClientError::TimedOut(timeout))?,
+            None => self.receive_inner(request_id).await,
+        }
+    }
+
+    async fn receive_inner<P: Packet>(&mut self, request_id: RequestId) -> Result<P, ClientError> {
+        loop {
+            let buf = self.compressor.read(&mut self.stream).await?;
+
+            match buf.id() {
+                id if id == P::ID => return Ok(buf.to_packet::<P>()?),
+                ProtocolError::ID => {
+                    let error = buf.to_packet::<ProtocolError>()?;
+
+                    if matches!(error.kind.request_id(), Some(id) if id != request_id) {
+                        log::warn!(
+                            "ignoring error about another request: {}",
+                            error.kind.message()
+                        );
+                        continue;
+                    }
+
+                    return Err(ClientError::Protocol(error));
+                }
+                Heartbeat::ID => {
+                    let heartbeat = buf.to_packet::<Heartbeat>()?;
+                    self.send(&heartbeat).await?;
+                }
+                id => return Err(ClientError::UnexpectedPacket(id)),
+            }
+        }
+    }
+}
@@ -1,8 +1,11 @@
 //! This module contains code related to the generator server's runtime.
 
-pub(crate) mod net;
+pub mod client;
+pub mod net;
 pub mod server;
 mod util;
 
+pub use util::{GenerationIdent, RequestIdent};
+
 #[cfg(test)]
 mod tests;
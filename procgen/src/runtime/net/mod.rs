@@ -3,80 +3,270 @@ pub mod packets;
 
 use std::{
     collections::HashMap,
-    io::{Read, Write},
-    net::{SocketAddr, SocketAddrV4},
+    io::{self, Read, Write},
+    net::SocketAddr,
     ops::DerefMut,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
     sync::{
-        mpsc::{Receiver, Sender},
-        Mutex, MutexGuard, RwLock,
+        mpsc::{self, Receiver, Sender, UnboundedSender},
+        oneshot, watch, Mutex, MutexGuard, Notify, RwLock,
     },
+    task::JoinHandle,
 };
 
-use self::packets::{DowncastPacket, Packet, PacketBuffer, ProtocolError, ProtocolErrorKind};
+use self::packets::{
+    DowncastPacket, Heartbeat, Packet, PacketBuffer, PacketBufferError, ProtocolError,
+    ProtocolErrorKind,
+};
 
 use super::server::ServerParams;
-use common::ConnectionId;
+use common::{ConnectionId, ConnectionIdDisplay};
 
 type DynPacket = Box<dyn DowncastPacket>;
 
 type Shared<T> = Arc<RwLock<T>>;
+
+/// The reading half of whatever transport a [`Connection`] is running over.
+pub(crate) type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+/// The writing half of whatever transport a [`Connection`] is running over.
+pub(crate) type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
 type ConnectionMap = HashMap<ConnectionId, Connection>;
+/// Secondary index of [`ConnectionMap`] for looking up connections by the address of the peer.
+type AddressIndex = HashMap<SocketAddr, Vec<ConnectionId>>;
+
+/// Callback invoked for every connection that closes, see [`Networker::set_close_hook`].
+pub(crate) type CloseHook = dyn Fn(ConnectionId) + Send + Sync;
+
+pub(crate) use common::PROTOCOL_VERSION;
+
+/// Protocol version from before headers had an algorithm byte. Headers with this version are still accepted, their
+/// packets are always zlib compressed.
+pub(crate) const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+/// Default limit for the compressed and decompressed length of a packet, see [`Compressor::with_max_packet_size`].
+pub(crate) const DEFAULT_MAX_PACKET_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Algorithm used to compress packet bodies. The algorithm is sent in the header of every packet, so peers can read
+/// packets no matter which algorithm they compress their own packets with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompressionAlgo {
+    Zlib(Compression),
+    /// Zstandard with the given compression level.
+    Zstd(i32),
+    Lz4,
+    /// Send packets uncompressed.
+    None,
+}
+
+impl CompressionAlgo {
+    /// The algorithm's ID in packet headers.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::Zlib(_) => 0,
+            Self::Zstd(_) => 1,
+            Self::Lz4 => 2,
+            Self::None => 3,
+        }
+    }
+
+    fn compress(self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zlib(level) => {
+                let mut compressed = Vec::<u8>::new();
+                let mut encoder = ZlibEncoder::new(&mut compressed, level);
+                encoder.write_all(buf)?;
+                encoder.finish()?;
+
+                Ok(compressed)
+            }
+            Self::Zstd(level) => zstd::bulk::compress(buf, level),
+            Self::Lz4 => Ok(lz4_flex::compress(buf)),
+            Self::None => Ok(buf.to_vec()),
+        }
+    }
+
+    /// Decompress `buf` with the algorithm that has the header ID `id`.
+    /// `decompressed_len` is the length given in the packet's header. Decompression stops (or fails) shortly after
+    /// producing that many bytes, so a tiny packet can't decompress into gigabytes of memory.
+    fn decompress(id: u8, buf: &[u8], decompressed_len: usize) -> Result<Vec<u8>, CompressorError> {
+        let decompressed = match id {
+            0 => {
+                let mut decompressed = Vec::<u8>::with_capacity(decompressed_len);
+                // Reading one byte past the expected length is enough for the caller to notice the mismatch.
+                ZlibDecoder::new(buf)
+                    .take(decompressed_len as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map(|_| decompressed)
+            }
+            1 => zstd::bulk::decompress(buf, decompressed_len),
+            2 => lz4_flex::decompress(buf, decompressed_len)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            3 => Ok(buf.to_vec()),
+            id => return Err(CompressorError::UnknownAlgorithm(id)),
+        };
+
+        decompressed.map_err(CompressorError::Decompress)
+    }
+}
+
+impl Default for CompressionAlgo {
+    fn default() -> Self {
+        Self::Zlib(Compression::best())
+    }
+}
+
+impl From<Compression> for CompressionAlgo {
+    fn from(level: Compression) -> Self {
+        Self::Zlib(level)
+    }
+}
+
+#[derive(te::Error, Debug)]
+pub(crate) enum HeaderError {
+    #[error("IO error when reading or writing header: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Mismatched protocol version in header (expected {expected}, got {actual})")]
+    MismatchedVersion { expected: u8, actual: u8 },
+    #[error("Header specified a packet of {len} bytes, but packets can be at most {max} bytes")]
+    TooLarge { len: u32, max: u32 },
+}
 
-/// Represents a packet header, containing the packet's compressed length and decompressed length.
+/// Represents a packet header, containing the protocol version, the compression algorithm, and the packet's compressed
+/// length and decompressed length.
 /// The packet's compressed length is the actual size the packet takes up in the TCP stream.
 /// For example, if a header with a compressed length of 20 is sent, that means the next 20 bytes after
 /// the header are part of a compressed packet. So a reader should read 20 bytes after the header.
 ///
 /// The decompressed length should be used for error checking and optimizations.
+///
+/// The algorithm is the [`CompressionAlgo::id`] of the algorithm the packet was compressed with.
+///
+/// The version is not stored in the header itself, it's always [`PROTOCOL_VERSION`] when writing. Reading also accepts
+/// [`LEGACY_PROTOCOL_VERSION`] headers (which have no algorithm byte) as zlib, any other version fails with
+/// [`HeaderError::MismatchedVersion`].
 #[derive(Copy, Clone, Debug)]
 pub(crate) struct Header {
+    pub(crate) algorithm: u8,
     pub(crate) compressed_len: u32,
     pub(crate) decompressed_len: u32,
 }
 
 impl Header {
+    /// Create a header for a packet compressed with zlib. Use [`Header::with_algorithm`] for other algorithms.
     pub(crate) fn new(compressed_len: u32, decompressed_len: u32) -> Self {
         Self {
+            algorithm: CompressionAlgo::Zlib(Compression::best()).id(),
             compressed_len,
             decompressed_len,
         }
     }
 
-    pub(crate) async fn read<S: AsyncReadExt + Unpin>(s: &mut S) -> anyhow::Result<Self> {
+    pub(crate) fn with_algorithm(mut self, algorithm: CompressionAlgo) -> Self {
+        self.algorithm = algorithm.id();
+        self
+    }
+
+    /// Check that we understand headers with `version`, returning whether they have an algorithm byte.
+    #[inline]
+    fn check_version(version: u8) -> Result<bool, HeaderError> {
+        match version {
+            PROTOCOL_VERSION => Ok(true),
+            LEGACY_PROTOCOL_VERSION => Ok(false),
+            _ => Err(HeaderError::MismatchedVersion {
+                expected: PROTOCOL_VERSION,
+                actual: version,
+            }),
+        }
+    }
+
+    /// Check that neither of the packet's lengths exceed `max_len`, so we don't allocate whatever a peer tells us to.
+    #[inline]
+    fn check_len(self, max_len: u32) -> Result<Self, HeaderError> {
+        let len = self.compressed_len.max(self.decompressed_len);
+        if len > max_len {
+            return Err(HeaderError::TooLarge { len, max: max_len });
+        }
+
+        Ok(self)
+    }
+
+    /// Read a header, failing with [`HeaderError::TooLarge`] if it specifies a packet longer than `max_len` bytes.
+    pub(crate) async fn read<S: AsyncReadExt + Unpin>(
+        s: &mut S,
+        max_len: u32,
+    ) -> Result<Self, HeaderError> {
+        let version = s.read_u8().await?;
+        Self::read_after_version(version, s, max_len).await
+    }
+
+    /// Like [`Header::read`], but for when the header's first byte (the protocol version) has already been read.
+    async fn read_after_version<S: AsyncReadExt + Unpin>(
+        version: u8,
+        s: &mut S,
+        max_len: u32,
+    ) -> Result<Self, HeaderError> {
+        let algorithm = if Self::check_version(version)? {
+            s.read_u8().await?
+        } else {
+            CompressionAlgo::Zlib(Compression::best()).id()
+        };
         let compressed_len = s.read_u32().await?;
         let decompressed_len = s.read_u32().await?;
 
-        Ok(Self::new(compressed_len, decompressed_len))
+        Self {
+            algorithm,
+            compressed_len,
+            decompressed_len,
+        }
+        .check_len(max_len)
     }
 
-    pub(crate) async fn write<S: AsyncWriteExt + Unpin>(&self, s: &mut S) -> anyhow::Result<()> {
+    pub(crate) async fn write<S: AsyncWriteExt + Unpin>(
+        &self,
+        s: &mut S,
+    ) -> Result<(), HeaderError> {
+        s.write_u8(PROTOCOL_VERSION).await?;
+        s.write_u8(self.algorithm).await?;
         s.write_u32(self.compressed_len).await?;
         s.write_u32(self.decompressed_len).await?;
 
         Ok(())
     }
 
-    pub(crate) fn sync_write<S: Write>(&self, s: &mut S) -> anyhow::Result<()> {
+    pub(crate) fn sync_write<S: Write>(&self, s: &mut S) -> Result<(), HeaderError> {
+        s.write_all(&[PROTOCOL_VERSION, self.algorithm])?;
         s.write_all(&self.compressed_len.to_be_bytes())?;
         s.write_all(&self.decompressed_len.to_be_bytes())?;
 
         Ok(())
     }
 
-    pub(crate) fn sync_read<R: Read>(r: &mut R) -> anyhow::Result<Self> {
+    /// Like [`Header::read`], but blocking.
+    pub(crate) fn sync_read<R: Read>(r: &mut R, max_len: u32) -> Result<Self, HeaderError> {
+        let version = {
+            let mut buf = [0u8; 1];
+            r.read_exact(&mut buf)?;
+            buf[0]
+        };
+
+        let algorithm = if Self::check_version(version)? {
+            let mut buf = [0u8; 1];
+            r.read_exact(&mut buf)?;
+            buf[0]
+        } else {
+            CompressionAlgo::Zlib(Compression::best()).id()
+        };
+
         let comp_l = {
             let mut buf = [0u8; 4];
             r.read_exact(&mut buf)?;
@@ -89,67 +279,201 @@ impl Header {
             u32::from_be_bytes(buf)
         };
 
-        Ok(Self {
+        Self {
+            algorithm,
             compressed_len: comp_l,
             decompressed_len: decomp_l,
-        })
+        }
+        .check_len(max_len)
+    }
+}
+
+#[derive(te::Error, Debug)]
+pub enum CompressorError {
+    #[error("IO error when reading or writing packet: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to decompress packet: {0}")]
+    Decompress(io::Error),
+    #[error("Packet of {0} bytes is too large")]
+    OversizedPacket(usize),
+    #[error("Header specified a decompressed length of {expected} bytes, but the packet was {actual} bytes")]
+    LengthMismatch { expected: u32, actual: usize },
+    #[error("Stream was closed")]
+    Eof,
+    #[error("Mismatched protocol version in header (expected {expected}, got {actual})")]
+    MismatchedVersion { expected: u8, actual: u8 },
+    #[error("Invalid packet: {0}")]
+    InvalidPacket(#[from] PacketBufferError),
+    #[error("Packet was compressed with unknown algorithm {0}")]
+    UnknownAlgorithm(u8),
+    #[error("Timed out after {0:?}")]
+    TimedOut(Duration),
+}
+
+impl From<HeaderError> for CompressorError {
+    fn from(error: HeaderError) -> Self {
+        match error {
+            HeaderError::IoError(error) => Self::Io(error),
+            HeaderError::MismatchedVersion { expected, actual } => {
+                Self::MismatchedVersion { expected, actual }
+            }
+            HeaderError::TooLarge { len, .. } => Self::OversizedPacket(len as usize),
+        }
     }
 }
 
 #[derive(Copy, Clone)]
 pub struct Compressor {
-    level: Compression,
+    algorithm: CompressionAlgo,
+    threshold: usize,
+    max_packet_size: u32,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
 }
 
 impl Compressor {
-    pub fn new(level: Compression) -> Self {
-        Self { level }
+    /// Create a compressor that compresses packets with `algorithm`. Packets are decompressed with whichever
+    /// algorithm their header specifies.
+    pub fn new(algorithm: CompressionAlgo) -> Self {
+        Self {
+            algorithm,
+            threshold: 0,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// Send packets smaller than `threshold` bytes uncompressed. Compressing tiny packets wastes time and often
+    /// makes them bigger. A threshold of 0 compresses every packet.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Fail reads with [`CompressorError::OversizedPacket`] if a packet's header says it's longer than `max` bytes
+    /// (compressed or decompressed), before anything is allocated for it.
+    pub fn with_max_packet_size(mut self, max: u32) -> Self {
+        self.max_packet_size = max;
+        self
+    }
+
+    /// Fail reads with [`CompressorError::TimedOut`] if no packet starts arriving within `timeout`.
+    /// Peers don't have to send anything while they're idle, so this is usually `None`.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Fail reads with [`CompressorError::TimedOut`] if the rest of a packet (header and body) takes longer than
+    /// `timeout` to arrive after its first byte.
+    /// This stops peers from hogging a connection by starting a packet and then dribbling out the rest of it.
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Fail writes with [`CompressorError::TimedOut`] if writing a packet takes longer than `timeout`.
+    pub fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Run `fut`, failing with [`CompressorError::TimedOut`] if it doesn't finish within `timeout`.
+    async fn timeout<T, F>(timeout: Option<Duration>, fut: F) -> Result<T, CompressorError>
+    where
+        F: std::future::Future<Output = Result<T, CompressorError>>,
+    {
+        match timeout {
+            Some(duration) => tokio::time::timeout(duration, fut)
+                .await
+                .map_err(|_| CompressorError::TimedOut(duration))?,
+            None => fut.await,
+        }
     }
 
     pub async fn write<S: AsyncWriteExt + Unpin>(
         &self,
         packet: &PacketBuffer,
         stream: &mut S,
-    ) -> anyhow::Result<()> {
-        let decompressed_len = packet.len() as u32;
-
-        let compressed_buf = {
-            let mut buf = Vec::<u8>::new();
-            let mut encoder = ZlibEncoder::new(&mut buf, self.level);
-            encoder.write_all(packet.as_ref())?;
-            encoder.finish()?;
-
-            buf
+    ) -> Result<(), CompressorError> {
+        let decompressed_len = u32::try_from(packet.len())
+            .map_err(|_| CompressorError::OversizedPacket(packet.len()))?;
+
+        let algorithm = if packet.len() < self.threshold {
+            CompressionAlgo::None
+        } else {
+            self.algorithm
         };
 
-        let compressed_len = compressed_buf.len() as u32;
+        let compressed_buf = algorithm.compress(packet.as_ref())?;
 
-        Header::new(compressed_len, decompressed_len)
-            .write(stream)
-            .await?;
-        stream.write_all(&compressed_buf).await?;
-        stream.flush().await?;
+        let compressed_len = u32::try_from(compressed_buf.len())
+            .map_err(|_| CompressorError::OversizedPacket(compressed_buf.len()))?;
 
-        Ok(())
+        Self::timeout(self.write_timeout, async {
+            Header::new(compressed_len, decompressed_len)
+                .with_algorithm(algorithm)
+                .write(stream)
+                .await?;
+            stream.write_all(&compressed_buf).await?;
+            stream.flush().await?;
+
+            Ok(())
+        })
+        .await
     }
 
+    /// Read a packet from the stream.
+    /// Returns [`CompressorError::Eof`] if the stream was closed before a header could be read, which is how
+    /// a peer closing the connection normally looks.
     pub async fn read<S: AsyncReadExt + Unpin>(
         &self,
         stream: &mut S,
-    ) -> anyhow::Result<PacketBuffer> {
-        let header = Header::read(stream).await?;
+    ) -> Result<PacketBuffer, CompressorError> {
+        let version =
+            match Self::timeout(self.idle_timeout, async { Ok(stream.read_u8().await?) }).await {
+                Err(CompressorError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(CompressorError::Eof)
+                }
+                result => result?,
+            };
+
+        let (header, compressed_buf) = Self::timeout(self.read_timeout, async {
+            let header =
+                match Header::read_after_version(version, stream, self.max_packet_size).await {
+                    Err(HeaderError::IoError(error))
+                        if error.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        return Err(CompressorError::Eof)
+                    }
+                    result => result?,
+                };
 
-        let compressed_buf = {
             let mut buf = vec![0u8; header.compressed_len as usize];
 
             stream.read_exact(&mut buf).await?;
 
-            buf
-        };
-
-        let mut decompressor = ZlibDecoder::new(&compressed_buf[..]);
+            Ok((header, buf))
+        })
+        .await?;
+
+        let decompressed_buf = CompressionAlgo::decompress(
+            header.algorithm,
+            &compressed_buf,
+            header.decompressed_len as usize,
+        )?;
+
+        if decompressed_buf.len() != header.decompressed_len as usize {
+            return Err(CompressorError::LengthMismatch {
+                expected: header.decompressed_len,
+                actual: decompressed_buf.len(),
+            });
+        }
 
-        let buf = PacketBuffer::from_reader(&mut decompressor)?;
+        let buf = PacketBuffer::from_reader(&mut &decompressed_buf[..])?;
         Ok(buf)
     }
 }
@@ -166,47 +490,121 @@ impl<'a> Iterator for ConnectionIncoming<'a> {
     }
 }
 
+/// Messages for a connection's writer task.
+#[derive(Debug)]
+enum Outgoing {
+    Packet(PacketBuffer),
+    /// Write a final packet and close the connection, then report how it went through the sender.
+    Terminate(PacketBuffer, oneshot::Sender<Result<(), CompressorError>>),
+}
+
 #[derive(Clone)]
 pub struct Connection {
-    read: Arc<Mutex<BufReader<OwnedReadHalf>>>,
+    read: Arc<Mutex<BufReader<BoxedReader>>>,
     read_rx: Option<Arc<Mutex<Receiver<PacketBuffer>>>>,
 
-    write: Arc<Mutex<BufWriter<OwnedWriteHalf>>>,
-    write_tx: Option<Arc<Mutex<Sender<PacketBuffer>>>>,
+    write: Arc<Mutex<BufWriter<BoxedWriter>>>,
+    write_tx: Option<Arc<Mutex<Sender<Outgoing>>>>,
 
     running: Arc<AtomicBool>,
+    tasks: Arc<ConnectionTasks>,
+    /// Notified whenever a packet is received, see [`Connection::with_receive_notify`].
+    received: Arc<Notify>,
+    /// Sent this connection's ID once it closes, see [`Connection::with_closed_sender`].
+    closed: Option<UnboundedSender<ConnectionId>>,
 
     compressor: Compressor,
+    heartbeat_interval: Option<Duration>,
+    heartbeat_timeout: Duration,
     id: ConnectionId,
+    redact_address: bool,
+}
+
+/// Handles to a connection's reader and writer tasks. Shared between all clones of a [`Connection`], and aborts the
+/// tasks when the last clone is dropped so they don't outlive the connection.
+#[derive(Default)]
+struct ConnectionTasks(std::sync::Mutex<Vec<JoinHandle<()>>>);
+
+impl ConnectionTasks {
+    fn push(&self, task: JoinHandle<()>) {
+        self.0.lock().unwrap().push(task);
+    }
+}
+
+impl Drop for ConnectionTasks {
+    fn drop(&mut self) {
+        if let Ok(tasks) = self.0.get_mut() {
+            for task in tasks.drain(..) {
+                task.abort();
+            }
+        }
+    }
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, compression: Compression) -> Self {
-        let addr = stream.peer_addr().unwrap();
+    /// Create a connection over an accepted TCP stream. `addr` is the peer's address as reported by the listener,
+    /// since asking the stream for it fails if the peer has already disconnected.
+    pub(crate) fn new(stream: TcpStream, addr: SocketAddr, seq: u64, params: &Params) -> Self {
         let (read, write) = stream.into_split();
 
-        let id = {
-            match addr {
-                SocketAddr::V4(addr) => ConnectionId(addr),
-                _ => panic!("invalid address"),
-            }
-        };
+        let id = ConnectionId::new(addr, seq);
 
+        Self::from_parts(Box::new(read), Box::new(write), id, params)
+    }
+
+    /// Create a connection running over any transport, not just TCP (e.g., Unix sockets or in-memory pipes).
+    /// Transports without a peer address still need an `id`, which should be unique among the networker's connections.
+    pub(crate) fn from_parts(
+        read: BoxedReader,
+        write: BoxedWriter,
+        id: ConnectionId,
+        params: &Params,
+    ) -> Self {
         Self {
             read: Mutex::new(BufReader::new(read)).into(),
             read_rx: None,
             write: Mutex::new(BufWriter::new(write)).into(),
             write_tx: None,
             running: Arc::new(false.into()),
-            compressor: Compressor::new(compression),
+            tasks: Arc::default(),
+            received: Arc::default(),
+            closed: None,
+            compressor: Compressor::new(params.compression)
+                .with_threshold(params.compression_threshold)
+                .with_max_packet_size(params.max_packet_size)
+                .with_idle_timeout(params.idle_timeout)
+                .with_read_timeout(params.read_timeout)
+                .with_write_timeout(params.write_timeout),
+            heartbeat_interval: params.heartbeat_interval,
+            heartbeat_timeout: params.heartbeat_timeout,
             id,
+            redact_address: params.redact_addresses,
         }
     }
 
+    /// Notify `notify` whenever this connection receives a packet, so packets can be waited for (possibly from many
+    /// connections at once) instead of polled. Must be set before the connection is started.
+    pub(crate) fn with_receive_notify(mut self, notify: Arc<Notify>) -> Self {
+        self.received = notify;
+        self
+    }
+
+    /// Send this connection's ID through `closed` once the connection closes (e.g., because the peer hung up or
+    /// timed out), so whoever is keeping track of it can let go of it. Must be set before the connection is started.
+    pub(crate) fn with_closed_sender(mut self, closed: UnboundedSender<ConnectionId>) -> Self {
+        self.closed = Some(closed);
+        self
+    }
+
     pub fn id(&self) -> ConnectionId {
         self.id
     }
 
+    /// The ID of this connection as it should appear in logs (redacted if the networker was configured to do so).
+    pub fn display_id(&self) -> ConnectionIdDisplay {
+        self.id.display(self.redact_address)
+    }
+
     pub async fn send_packet<P: Packet>(&self, packet: &P) -> anyhow::Result<()> {
         let raw = packet.to_bincode()?;
 
@@ -215,7 +613,7 @@ impl Connection {
             .unwrap()
             .lock()
             .await
-            .send(raw)
+            .send(Outgoing::Packet(raw))
             .await?;
 
         Ok(())
@@ -236,26 +634,109 @@ impl Connection {
         self.running.store(true, Ordering::SeqCst);
 
         let (read_tx, read_rx) = tokio::sync::mpsc::channel::<PacketBuffer>(128);
-        let (write_tx, mut write_rx) = tokio::sync::mpsc::channel::<PacketBuffer>(128);
+        let (write_tx, mut write_rx) = tokio::sync::mpsc::channel::<Outgoing>(128);
 
         self.read_rx = Some(Arc::new(Mutex::new(read_rx)));
-        self.write_tx = Some(Arc::new(Mutex::new(write_tx)));
+        self.write_tx = Some(Arc::new(Mutex::new(write_tx.clone())));
+
+        // Nonce of the last heartbeat we sent, and of the last heartbeat the peer echoed back.
+        let heartbeat_sent = Arc::new(AtomicU64::new(0));
+        let heartbeat_acked = Arc::new(AtomicU64::new(0));
+
+        let report_closed = {
+            let closed = self.closed.clone();
+            let conn_id = self.id;
+
+            move || {
+                if let Some(ref closed) = closed {
+                    closed.send(conn_id).ok();
+                }
+            }
+        };
 
         // Reader
         let reader = self.read.clone();
+        let writer = self.write.clone();
         let compressor = self.compressor;
         let running = self.running.clone();
-        let id = self.id();
-        tokio::spawn(async move {
+        let id = self.display_id();
+        let received = self.received.clone();
+        let sent = heartbeat_sent.clone();
+        let acked = heartbeat_acked.clone();
+        let terminate_tx = write_tx.clone();
+        let closed = report_closed.clone();
+        let reader_task = tokio::spawn(async move {
             while running.load(Ordering::SeqCst) {
-                for _ in 0..100 {
-                    let mut guard = reader.lock().await;
-                    match compressor.read(guard.deref_mut()).await {
-                        Ok(raw) => read_tx.send(raw).await.unwrap(),
-                        Err(error) => {
-                            log::warn!("error reading packet from {id}: {error}")
+                let mut guard = reader.lock().await;
+                match compressor.read(guard.deref_mut()).await {
+                    // Heartbeats are answered by the connection itself, so they're not passed on.
+                    Ok(raw) if raw.id() == Heartbeat::ID => {
+                        match raw.to_packet::<Heartbeat>() {
+                            // A peer echoing nonces we haven't sent yet could otherwise keep itself alive
+                            // without answering our heartbeats.
+                            Ok(heartbeat) if heartbeat.nonce > sent.load(Ordering::SeqCst) => {
+                                log::warn!(
+                                    "{id} echoed heartbeat {}, which was never sent",
+                                    heartbeat.nonce
+                                );
+                            }
+                            Ok(heartbeat) => {
+                                acked.fetch_max(heartbeat.nonce, Ordering::SeqCst);
+                            }
+                            Err(error) => log::warn!("invalid heartbeat from {id}: {error}"),
                         }
                     }
+                    Ok(raw) => {
+                        read_tx.send(raw).await.unwrap();
+                        received.notify_one();
+                    }
+                    Err(CompressorError::Eof) => {
+                        log::info!("connection {id} was closed by peer");
+                        running.store(false, Ordering::SeqCst);
+                        closed();
+                        break;
+                    }
+                    // The stream is either dead or stuck, so there's no one left to tell.
+                    Err(error @ (CompressorError::TimedOut(_) | CompressorError::Io(_))) => {
+                        log::warn!("dropping connection {id}, reading packet failed: {error}");
+                        running.store(false, Ordering::SeqCst);
+                        // Shutting down our half lets the peer know that we're not talking to it anymore.
+                        writer.lock().await.shutdown().await.ok();
+                        closed();
+                        break;
+                    }
+                    Err(
+                        error @ (CompressorError::OversizedPacket(_)
+                        | CompressorError::MismatchedVersion { .. }),
+                    ) => {
+                        log::warn!("dropping connection {id}, reading packet failed: {error}");
+                        running.store(false, Ordering::SeqCst);
+
+                        // We don't know where the next packet starts (we either skipped this packet's body or
+                        // can't even parse its header), so we can't make sense of the stream anymore.
+                        // Let the peer know why we're hanging up on it. The writer reports the connection as
+                        // closed once it's done with it.
+                        let packet = ProtocolError::fatal(ProtocolErrorKind::Other {
+                            details: error.to_string(),
+                        })
+                        .to_bincode()
+                        .unwrap();
+                        let (ack, _) = oneshot::channel();
+                        terminate_tx
+                            .send(Outgoing::Terminate(packet, ack))
+                            .await
+                            .ok();
+                        break;
+                    }
+                    // The whole packet was consumed, so the stream is still in sync and we can carry on.
+                    Err(
+                        error @ (CompressorError::Decompress(_)
+                        | CompressorError::LengthMismatch { .. }
+                        | CompressorError::UnknownAlgorithm(_)
+                        | CompressorError::InvalidPacket(_)),
+                    ) => {
+                        log::warn!("error reading packet from {id}: {error}")
+                    }
                 }
             }
         });
@@ -264,31 +745,118 @@ impl Connection {
         let writer = self.write.clone();
         let compressor = self.compressor;
         let running = self.running.clone();
-        tokio::spawn(async move {
-            while running.load(Ordering::SeqCst) {
-                for _ in 0..100 {
-                    if let Ok(raw) = write_rx.try_recv() {
-                        compressor
-                            .write(&raw, writer.lock().await.deref_mut())
-                            .await
-                            .unwrap();
+        let id = self.display_id();
+        let closed = report_closed;
+        let writer_task = tokio::spawn(async move {
+            // Sleep until there's something to write. The loop ends once the connection is terminated or dropped.
+            while let Some(outgoing) = write_rx.recv().await {
+                let mut guard = writer.lock().await;
+                match outgoing {
+                    Outgoing::Packet(raw) => {
+                        match compressor.write(&raw, guard.deref_mut()).await {
+                            Ok(()) => (),
+                            // Either the stream is dead or stuck, or we don't know how much of the packet made it
+                            // out. In any case the peer can't make sense of anything we send after this.
+                            Err(error) => {
+                                log::warn!(
+                                    "dropping connection {id}, writing packet failed: {error}"
+                                );
+                                running.store(false, Ordering::SeqCst);
+                                closed();
+                                break;
+                            }
+                        }
+                    }
+                    Outgoing::Terminate(raw, ack) => {
+                        // Nothing can be queued after this, and everything queued before it has already been
+                        // written, so this is guaranteed to be the last packet the peer receives.
+                        write_rx.close();
+
+                        let result = compressor.write(&raw, guard.deref_mut()).await;
+                        guard.shutdown().await.ok();
+
+                        running.store(false, Ordering::SeqCst);
+                        ack.send(result).ok();
+                        closed();
+                        break;
                     }
                 }
             }
         });
+
+        self.tasks.push(reader_task);
+        self.tasks.push(writer_task);
+
+        // Heartbeats
+        if let Some(interval) = self.heartbeat_interval {
+            let timeout = self.heartbeat_timeout;
+            let running = self.running.clone();
+            let id = self.display_id();
+            let heartbeat_task = tokio::spawn(async move {
+                let mut ticks =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+                while running.load(Ordering::SeqCst) {
+                    ticks.tick().await;
+
+                    // Heartbeats are sent once per interval, so the oldest unanswered one was sent this long ago.
+                    let unanswered = heartbeat_sent
+                        .load(Ordering::SeqCst)
+                        .saturating_sub(heartbeat_acked.load(Ordering::SeqCst));
+                    if interval * unanswered as u32 >= timeout {
+                        log::warn!(
+                            "dropping connection {id}, {unanswered} heartbeats went unanswered"
+                        );
+                        running.store(false, Ordering::SeqCst);
+
+                        let packet = ProtocolError::fatal(ProtocolErrorKind::Terminated {
+                            details: "heartbeat timed out".to_string(),
+                        })
+                        .to_bincode()
+                        .unwrap();
+                        let (ack, _) = oneshot::channel();
+                        write_tx.send(Outgoing::Terminate(packet, ack)).await.ok();
+                        break;
+                    }
+
+                    let nonce = heartbeat_sent.fetch_add(1, Ordering::SeqCst) + 1;
+                    let packet = Heartbeat { nonce }.to_bincode().unwrap();
+                    if write_tx.send(Outgoing::Packet(packet)).await.is_err() {
+                        // The writer is gone, so the connection is closed.
+                        break;
+                    }
+                }
+            });
+
+            self.tasks.push(heartbeat_task);
+        }
     }
 
     pub async fn terminate(&self) -> anyhow::Result<()> {
         let packet = ProtocolError::fatal(ProtocolErrorKind::Terminated {
             details: "Server stopped".to_string(),
-        });
+        })
+        .to_bincode()?;
+
+        match self.write_tx {
+            // Go through the writer task so the packet is written after any packets that are still queued.
+            Some(ref write_tx) => {
+                let (ack_tx, ack_rx) = oneshot::channel();
+                write_tx
+                    .lock()
+                    .await
+                    .send(Outgoing::Terminate(packet, ack_tx))
+                    .await?;
 
-        self.compressor
-            .write(
-                &packet.to_bincode().unwrap(),
-                self.write.lock().await.deref_mut(),
-            )
-            .await?;
+                ack_rx.await??;
+            }
+            // The connection was never started, so there's no writer task to go through.
+            None => {
+                let mut guard = self.write.lock().await;
+                self.compressor.write(&packet, guard.deref_mut()).await?;
+                guard.shutdown().await?;
+            }
+        }
 
         self.running.store(false, Ordering::SeqCst);
         Ok(())
@@ -298,46 +866,91 @@ impl Connection {
 impl std::fmt::Debug for Connection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Connection")
-            .field("address", &self.id)
-            .field("compression", &self.compressor.level)
+            .field("address", &self.display_id())
+            .field("compression", &self.compressor.algorithm)
             .field("running", &self.running.load(Ordering::SeqCst))
             .finish()
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub(crate) struct Params {
-    pub(crate) addr: SocketAddrV4,
-    pub(crate) compression: Compression,
+    pub(crate) addrs: Vec<SocketAddr>,
+    pub(crate) compression: CompressionAlgo,
+    pub(crate) compression_threshold: usize,
+    pub(crate) max_packet_size: u32,
+    pub(crate) redact_addresses: bool,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) heartbeat_timeout: Duration,
 }
 
 impl From<ServerParams> for Params {
     fn from(p: ServerParams) -> Self {
         Self {
-            addr: p.addr,
+            addrs: p.addrs,
             compression: p.compression,
+            compression_threshold: p.compression_threshold,
+            max_packet_size: p.max_packet_size,
+            redact_addresses: p.redact_addresses,
+            idle_timeout: p.idle_timeout,
+            read_timeout: p.read_timeout,
+            write_timeout: p.write_timeout,
+            heartbeat_interval: p.heartbeat_interval,
+            heartbeat_timeout: p.heartbeat_timeout,
         }
     }
 }
 
+impl Default for Params {
+    fn default() -> Self {
+        ServerParams::default().into()
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct Networker {
     params: Params,
-    listener: Option<Arc<Mutex<TcpListener>>>,
     connections: Shared<ConnectionMap>,
+    addresses: Shared<AddressIndex>,
     running: Arc<AtomicBool>,
+    /// Tells the accept loops to stop, see [`Networker::stop`].
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Notified whenever any connection receives a packet.
+    received: Arc<Notify>,
+    /// Called once with the ID of every connection that closes, see [`Networker::set_close_hook`].
+    close_hook: Option<Arc<CloseHook>>,
 }
 
 impl Networker {
     pub fn new(params: Params) -> Self {
         Self {
             params,
-            listener: None,
             connections: Arc::new(RwLock::new(HashMap::new())),
+            addresses: Arc::new(RwLock::new(HashMap::new())),
             running: Arc::new(false.into()),
+            shutdown: Arc::new(watch::channel(false).0),
+            received: Arc::default(),
+            close_hook: None,
         }
     }
 
+    /// Set a hook that's called with the ID of every connection once it closes, after it's been forgotten.
+    /// Must be set before the networker is started.
+    pub fn set_close_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(ConnectionId) + Send + Sync + 'static,
+    {
+        assert!(
+            !self.running.load(Ordering::SeqCst),
+            "cannot set close hook while networker is running!"
+        );
+
+        self.close_hook = Some(Arc::new(hook));
+    }
+
     pub async fn run(&mut self) -> anyhow::Result<()> {
         assert!(
             !self.running.load(Ordering::SeqCst),
@@ -346,35 +959,120 @@ impl Networker {
 
         self.running.store(true, Ordering::SeqCst);
 
-        let listener = Arc::new(Mutex::new(TcpListener::bind(self.params.addr).await?));
-        self.listener = Some(listener.clone());
+        // Bind every address before accepting anything, so we fail early if one of them is unavailable.
+        let mut listeners = Vec::with_capacity(self.params.addrs.len());
+        for addr in &self.params.addrs {
+            listeners.push(TcpListener::bind(addr).await?);
+        }
 
-        let connections = self.connections.clone();
-        let compression = self.params.compression;
-        let running = self.running.clone();
+        // Connections are numbered across all listeners so their IDs stay unique.
+        let next_seq = Arc::new(AtomicU64::new(0));
+
+        // Forget connections once they close, otherwise they'd pile up (along with their transports) until we stop.
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel::<ConnectionId>();
+        {
+            let connections = self.connections.clone();
+            let addresses = self.addresses.clone();
+            let close_hook = self.close_hook.clone();
+
+            tokio::spawn(async move {
+                while let Some(id) = closed_rx.recv().await {
+                    let removed = connections.write().await.remove(&id);
+                    // Both of a connection's tasks can report it closing, so only the first report counts.
+                    if removed.is_none() {
+                        continue;
+                    }
 
-        tokio::spawn(async move {
-            while running.load(Ordering::SeqCst) {
-                for _ in 0..100 {
-                    let (incoming, _) = listener.lock().await.accept().await.unwrap();
+                    let mut addresses = addresses.write().await;
+                    if let Some(ids) = addresses.get_mut(&id.addr()) {
+                        ids.retain(|&other| other != id);
+                        if ids.is_empty() {
+                            addresses.remove(&id.addr());
+                        }
+                    }
+                    drop(addresses);
+
+                    if let Some(ref hook) = close_hook {
+                        hook(id);
+                    }
+
+                    // Dropping the last handle to the connection cleans up its remaining tasks.
+                    drop(removed);
+                }
+            });
+        }
+
+        // One accept loop per address, all feeding the same connections.
+        // Each loop owns its listener, so the address is released as soon as the loop exits.
+        for listener in listeners {
+            let connections = self.connections.clone();
+            let addresses = self.addresses.clone();
+            let params = self.params.clone();
+            let running = self.running.clone();
+            let next_seq = next_seq.clone();
+            let mut shutdown = self.shutdown.subscribe();
+            let received = self.received.clone();
+            let closed_tx = closed_tx.clone();
+
+            tokio::spawn(async move {
+                while running.load(Ordering::SeqCst) {
+                    let accepted = tokio::select! {
+                        accepted = listener.accept() => accepted,
+                        _ = shutdown.changed() => break,
+                    };
+
+                    let (incoming, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(error) => {
+                            log::warn!("error accepting connection: {error}");
+                            continue;
+                        }
+                    };
 
-                    let mut conn = Connection::new(incoming, compression);
+                    let seq = next_seq.fetch_add(1, Ordering::SeqCst);
+                    let mut conn = Connection::new(incoming, addr, seq, &params)
+                        .with_receive_notify(received.clone())
+                        .with_closed_sender(closed_tx.clone());
 
-                    log::info!("accepted connection from {}", conn.id());
+                    log::info!("accepted connection from {}", conn.display_id());
 
+                    // The connection must be in the maps by the time anyone looks for it, otherwise a packet it
+                    // receives (or it closing) right after starting could be handled before we've inserted it and
+                    // get lost. Holding the lock while starting it makes anyone looking wait until it's inserted.
+                    let mut guard = connections.write().await;
                     conn.run();
-                    connections.write().await.insert(conn.id(), conn);
+                    addresses
+                        .write()
+                        .await
+                        .entry(conn.id().addr())
+                        .or_default()
+                        .push(conn.id());
+                    guard.insert(conn.id(), conn);
                 }
-            }
-        });
+            });
+        }
 
         Ok(())
     }
 
+    /// Stop accepting connections and terminate all existing ones.
     pub async fn stop(self) -> anyhow::Result<()> {
         self.running.store(false, Ordering::SeqCst);
+        self.shutdown.send(true).ok();
+        // Wake up whoever is waiting for packets so they notice that we stopped.
+        self.received.notify_one();
+
+        // Take the connections out of the map so their tasks are cleaned up once we're done with them.
+        let connections = self
+            .connections
+            .write()
+            .await
+            .drain()
+            .map(|(_, conn)| conn)
+            .collect::<Vec<_>>();
+        self.addresses.write().await.clear();
 
-        for conn in self.connections.read().await.values() {
+        for conn in connections {
             if let Err(error) = conn.terminate().await {
                 log::warn!("Error when terminating connection {conn:?}: {error}");
             }
@@ -383,17 +1081,31 @@ impl Networker {
         Ok(())
     }
 
-    pub async fn incoming(&self) -> Incoming {
+    /// Wait until a packet was received by any connection since the last call. Returns immediately if packets
+    /// arrived in the meantime, so nothing is missed between draining and waiting again. Also returns when the
+    /// networker is stopped.
+    pub async fn wait_for_incoming(&self) {
+        self.received.notified().await
+    }
+
+    /// Drain the incoming packets of all connections into `buf`.
+    /// The packets are appended to the end of `buf`, so its allocation can be reused between calls
+    /// by clearing or draining it after use.
+    pub async fn drain_incoming(&self, buf: &mut Vec<(Connection, anyhow::Result<DynPacket>)>) {
         let guard = self.connections.read().await;
-        let mut packets = Vec::new();
 
-        for (_, conn) in guard.iter() {
-            packets.extend(
+        for conn in guard.values() {
+            buf.extend(
                 conn.incoming()
                     .await
                     .map(|p| (conn.clone(), packets::parse_dyn(&p))),
             );
         }
+    }
+
+    pub async fn incoming(&self) -> Incoming {
+        let mut packets = Vec::new();
+        self.drain_incoming(&mut packets).await;
 
         Incoming(packets.into_iter())
     }
@@ -402,6 +1114,19 @@ impl Networker {
     pub async fn connection(&self, id: ConnectionId) -> Option<Connection> {
         self.connections.read().await.get(&id).cloned()
     }
+
+    /// Get all connections from the peer address `addr`, oldest first.
+    pub async fn connections_from(&self, addr: SocketAddr) -> Vec<Connection> {
+        let ids = match self.addresses.read().await.get(&addr) {
+            Some(ids) => ids.clone(),
+            None => return Vec::new(),
+        };
+
+        let connections = self.connections.read().await;
+        ids.iter()
+            .filter_map(|id| connections.get(id).cloned())
+            .collect()
+    }
 }
 
 pub struct Incoming(std::vec::IntoIter<(Connection, anyhow::Result<DynPacket>)>);
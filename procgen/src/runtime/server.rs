@@ -1,34 +1,39 @@
 use std::{
     collections::HashMap,
-    net::SocketAddrV4,
+    net::{Ipv4Addr, SocketAddr},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use anyhow::Error;
-use flate2::Compression;
 use threadpool::ThreadPool;
 use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, Semaphore};
 
 use crate::generation::{ChunkGenerator, DynChunkGenerator, DynGeneratorFactory};
 use common::generation::{FactoryParameters, GenerationArgs};
 
 use super::{
     net::{
-        packets::{self, ProtocolError, ProtocolErrorKind, ReplyChunk},
-        Networker,
+        packets::{self, ProtocolError, ProtocolErrorKind, ReplyChunk, ReplyChunkDelta},
+        CompressionAlgo, Connection, Networker, DEFAULT_MAX_PACKET_SIZE,
     },
-    util::{GenerationIdent, RequestIdent},
+    GenerationIdent, RequestIdent,
 };
 
-use common::{Chunk, GeneratorId};
+use common::{Chunk, ConnectionId, GeneratorId, CHUNK_SIZE};
+
+/// How long clients are told to wait before retrying a request that was rejected because the server was busy.
+const BUSY_RETRY_AFTER: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 enum GenerationResult {
     Success(GenerationIdent, Chunk),
+    /// The reply to a [`packets::GenerateChunkDelta`], which is diffed on the worker so the distributor doesn't have to.
+    Delta(GenerationIdent, ReplyChunkDelta),
     Failure(GenerationIdent, Error),
 }
 
@@ -36,6 +41,7 @@ impl GenerationResult {
     fn ident(&self) -> GenerationIdent {
         match self {
             Self::Success(id, _) => *id,
+            Self::Delta(id, _) => *id,
             Self::Failure(id, _) => *id,
         }
     }
@@ -58,20 +64,97 @@ impl Iterator for CompletedChunksIterator {
     }
 }
 
+/// Check that a generated chunk is at the position it was requested at.
+/// Generators return whatever chunk they want, so a buggy generator could easily produce a chunk at the wrong position.
+fn chunk_at_requested_pos(chunk: &Chunk, args: &GenerationArgs) -> bool {
+    let [x, _, z] = chunk.bounding_box().min();
+    let expected = args.pos * CHUNK_SIZE;
+
+    x == i64::from(expected.x) && z == i64::from(expected.y)
+}
+
+/// Requests being generated and their cancellation flags, see [`GeneratorManager::cancel`].
+type InFlight = std::sync::Mutex<HashMap<RequestIdent, Arc<AtomicBool>>>;
+
+/// Remove the request with the cancellation flag `cancelled` from `in_flight`. Clients can reuse request IDs, so
+/// the entry is left alone if it belongs to a newer request with the same ID.
+fn remove_in_flight(
+    in_flight: &InFlight,
+    request_ident: RequestIdent,
+    cancelled: &Arc<AtomicBool>,
+) {
+    let mut in_flight = in_flight.lock().unwrap();
+
+    if matches!(in_flight.get(&request_ident), Some(flag) if Arc::ptr_eq(flag, cancelled)) {
+        in_flight.remove(&request_ident);
+    }
+}
+
+/// Cancel every request from the connection `connection_id` in `in_flight`, like [`GeneratorManager::cancel`] does
+/// for a single request. Returns how many requests were cancelled.
+fn cancel_connection(in_flight: &InFlight, connection_id: ConnectionId) -> usize {
+    let mut in_flight = in_flight.lock().unwrap();
+    let before = in_flight.len();
+
+    in_flight.retain(|request_ident, cancelled| {
+        if request_ident.connection_id == connection_id {
+            cancelled.store(true, Ordering::SeqCst);
+            false
+        } else {
+            true
+        }
+    });
+
+    before - in_flight.len()
+}
+
+/// Generate a chunk with `generator`, checking that it's at the requested position in debug builds.
+fn generate_checked(
+    generator: &dyn DynChunkGenerator,
+    args: &GenerationArgs,
+) -> anyhow::Result<Chunk> {
+    generator.generate(args).and_then(|chunk| {
+        // This check isn't free, so we only do it in debug builds to catch buggy generators during development.
+        if cfg!(debug_assertions) && !chunk_at_requested_pos(&chunk, args) {
+            Err(anyhow::anyhow!(
+                "generator produced a chunk with bounding box {:?}, but chunk {} was requested",
+                chunk.bounding_box(),
+                args.pos
+            ))
+        } else {
+            Ok(chunk)
+        }
+    })
+}
+
 #[derive(Debug, te::Error)]
-#[error("Generator not found with ID {0}")]
-struct ManagerSubmitError(GeneratorId);
+enum ManagerSubmitError {
+    #[error("Generator not found with ID {0}")]
+    GeneratorNotFound(GeneratorId),
+    #[error("Too many pending requests (at most {0} are allowed)")]
+    Busy(usize),
+}
 
 #[derive(Debug, te::Error)]
 #[error("Couldn't find generator factory with name '{0}'")]
 struct UnknownFactoryError<'a>(&'a str);
 
+#[derive(Debug, te::Error)]
+#[error("A generator factory with name '{0}' is already registered")]
+pub struct DuplicateFactoryError(pub &'static str);
+
 #[derive(Clone)]
 struct ChunkReceiver {
     rx: Arc<Mutex<Receiver<GenerationResult>>>,
+    notify: Arc<Notify>,
 }
 
 impl ChunkReceiver {
+    /// Wait until the generator pool has completed a chunk.
+    async fn notified(&self) {
+        self.notify.notified().await
+    }
+
     async fn completed(&self) -> CompletedChunksIterator {
         let mut chunks = Vec::new();
         let mut guard = self.rx.lock().await;
@@ -84,32 +167,49 @@ impl ChunkReceiver {
     }
 }
 
+/// A generator created by a client, along with the name of the factory that created it.
+struct GeneratorInstance {
+    factory: &'static str,
+    generator: Arc<Box<dyn DynChunkGenerator>>,
+}
+
 struct GeneratorManager {
     factories: HashMap<&'static str, Box<dyn DynGeneratorFactory>>,
-    instances: HashMap<GeneratorId, Arc<Box<dyn DynChunkGenerator>>>,
+    instances: HashMap<GeneratorId, GeneratorInstance>,
     workers: Mutex<ThreadPool>,
     channel_pair: (
         Sender<GenerationResult>,
         Arc<Mutex<Receiver<GenerationResult>>>,
     ),
+    notify: Arc<Notify>,
+    /// Permits for requests that are being generated, so a flood of requests can't queue up unbounded work.
+    pending: Arc<Semaphore>,
+    max_pending: usize,
+    /// Cancellation flags of the requests that are being generated, see [`GeneratorManager::cancel`].
+    in_flight: Arc<InFlight>,
 }
 
 impl GeneratorManager {
-    fn new() -> Self {
+    fn new(workers: ThreadPool, max_pending: usize) -> Self {
         Self {
             factories: HashMap::new(),
             instances: HashMap::new(),
-            workers: Mutex::new(Default::default()),
+            workers: Mutex::new(workers),
             channel_pair: {
                 let (tx, rx) = mpsc::channel::<GenerationResult>(128);
                 (tx, Arc::new(Mutex::new(rx)))
             },
+            notify: Arc::new(Notify::new()),
+            pending: Arc::new(Semaphore::new(max_pending)),
+            max_pending,
+            in_flight: Arc::default(),
         }
     }
 
     fn receiver(&self) -> ChunkReceiver {
         ChunkReceiver {
             rx: self.channel_pair.1.clone(),
+            notify: self.notify.clone(),
         }
     }
 
@@ -117,10 +217,13 @@ impl GeneratorManager {
         &self,
         generator_name: &'a str,
         factory_params: FactoryParameters<'_>,
-    ) -> Result<Box<dyn DynChunkGenerator>, UnknownFactoryError<'a>> {
+    ) -> Result<GeneratorInstance, UnknownFactoryError<'a>> {
         self.factories
-            .get(generator_name)
-            .map(|f| f.create(factory_params))
+            .get_key_value(generator_name)
+            .map(|(&factory, f)| GeneratorInstance {
+                factory,
+                generator: Arc::new(f.create(factory_params)),
+            })
             .ok_or(UnknownFactoryError(generator_name))
     }
 
@@ -141,34 +244,90 @@ impl GeneratorManager {
         let instance = self.create_gen_instance(generator_name, factory_params)?;
         let id = self.random_gen_id();
 
-        self.instances.insert(id, Arc::new(instance));
+        self.instances.insert(id, instance);
         Ok(id)
     }
 
+    /// Submit a chunk for generation. If `base_generator_id` is given, the chunk that generator produces at the same
+    /// position is generated too, and the reply is a [`ReplyChunkDelta`] against it instead of the whole chunk.
     pub async fn submit_chunk(
         &self,
         request_ident: RequestIdent,
         generator_id: GeneratorId,
+        base_generator_id: Option<GeneratorId>,
         args: GenerationArgs,
     ) -> Result<(), ManagerSubmitError> {
         let tx = self.channel_pair.0.clone();
-        let instance = self
-            .instances
-            .get(&generator_id)
-            .ok_or(ManagerSubmitError(generator_id))?
-            .clone();
+        let notify = self.notify.clone();
+        let instance = self.instance(generator_id)?;
+        let base_instance = base_generator_id.map(|id| self.instance(id)).transpose()?;
+
+        // The permit is held until the generated chunk has been handed off to the distributor.
+        let permit = self
+            .pending
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ManagerSubmitError::Busy(self.max_pending))?;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(request_ident, cancelled.clone());
+        let in_flight = self.in_flight.clone();
 
         self.workers.lock().await.execute(move || {
-            let result = GenerationResult::from_result(
-                instance.generate(&args),
-                request_ident.generation_ident(generator_id),
-            );
+            // Generators can't be interrupted, but we can skip requests that were cancelled while they were queued.
+            if cancelled.load(Ordering::SeqCst) {
+                remove_in_flight(&in_flight, request_ident, &cancelled);
+                return;
+            }
+
+            let generated = generate_checked(&**instance, &args);
+
+            // Removing the request first means it can't be cancelled anymore once we've decided to reply to it.
+            remove_in_flight(&in_flight, request_ident, &cancelled);
+            if cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let ident = request_ident.generation_ident(generator_id);
+            let result = match base_instance {
+                None => GenerationResult::from_result(generated, ident),
+                Some(base_instance) => {
+                    let delta = generated.and_then(|chunk| {
+                        let base = generate_checked(&**base_instance, &args)?;
+
+                        ReplyChunkDelta::new(request_ident.request_id, &chunk, &base).ok_or_else(
+                            || anyhow::anyhow!("base generator's chunks have a different height"),
+                        )
+                    });
+
+                    match delta {
+                        Ok(delta) => GenerationResult::Delta(ident, delta),
+                        Err(error) => GenerationResult::Failure(ident, error),
+                    }
+                }
+            };
             tx.blocking_send(result).unwrap();
+            notify.notify_one();
+
+            drop(permit);
         });
 
         Ok(())
     }
 
+    fn instance(
+        &self,
+        generator_id: GeneratorId,
+    ) -> Result<Arc<Box<dyn DynChunkGenerator>>, ManagerSubmitError> {
+        self.instances
+            .get(&generator_id)
+            .map(|instance| instance.generator.clone())
+            .ok_or(ManagerSubmitError::GeneratorNotFound(generator_id))
+    }
+
     pub async fn completed(&self) -> CompletedChunksIterator {
         let mut chunks = Vec::new();
         let mut guard = self.channel_pair.1.lock().await;
@@ -180,41 +339,156 @@ impl GeneratorManager {
         CompletedChunksIterator(chunks.into_iter())
     }
 
-    pub fn add_factory(&mut self, name: &'static str, factory: Box<dyn DynGeneratorFactory>) {
+    /// Register a generator factory under `name`. Names must be unique, registering a factory under a name that's
+    /// already taken fails and leaves the existing factory in place.
+    pub fn add_factory(
+        &mut self,
+        name: &'static str,
+        factory: Box<dyn DynGeneratorFactory>,
+    ) -> Result<(), DuplicateFactoryError> {
+        if self.factories.contains_key(name) {
+            return Err(DuplicateFactoryError(name));
+        }
+
         self.factories.insert(name, factory);
+        Ok(())
+    }
+
+    /// Remove the generator factory registered under `name`, returning whether there was one.
+    /// Generators created by the factory are removed too, so new requests to them fail with
+    /// [`ManagerSubmitError::GeneratorNotFound`]. Requests that were already submitted still finish, since they hold
+    /// on to their generator.
+    pub fn remove_factory(&mut self, name: &str) -> bool {
+        let removed = self.factories.remove(name).is_some();
+        if removed {
+            self.instances
+                .retain(|_, instance| instance.factory != name);
+        }
+
+        removed
+    }
+
+    /// Cancel a request that's being generated, so no reply is sent for it. Generators are never interrupted, so a
+    /// chunk that's already generating still finishes, but its reply is dropped. If the client reused the request's
+    /// ID while an older request with the same ID was still generating, only the newest one is cancelled.
+    /// Returns `false` if the request isn't being generated (e.g., because it already completed).
+    fn cancel(&self, request_ident: RequestIdent) -> bool {
+        match self.in_flight.lock().unwrap().remove(&request_ident) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many chunk requests are currently being generated.
+    fn active_requests(&self) -> usize {
+        self.max_pending - self.pending.available_permits()
+    }
+
+    pub fn has_generator(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    pub fn generator_names(&self) -> Vec<String> {
+        self.factories.keys().map(|name| name.to_string()).collect()
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct ServerParams {
-    pub(crate) addr: SocketAddrV4,
-    pub(crate) compression: Compression,
-    pub(crate) coarsening: u32,
+    /// Addresses to listen for clients on, IPv4 or IPv6 (e.g., `[::]:44332`).
+    /// Clients connecting to any of them are served the same.
+    pub addrs: Vec<SocketAddr>,
+    /// How packets sent to clients are compressed.
+    pub compression: CompressionAlgo,
+    /// Packets smaller than this many bytes are sent uncompressed. 0 compresses every packet, which is the default
+    /// since the plugin only understands zlib compressed packets.
+    pub compression_threshold: usize,
+    /// Largest packet (compressed or decompressed) clients may send, in bytes. Clients sending anything larger are
+    /// disconnected, so they can't make the server allocate arbitrary amounts of memory.
+    pub max_packet_size: u32,
+    /// Redact client addresses in logs, see [`common::ConnectionId::redacted`].
+    pub redact_addresses: bool,
+    /// How long a client may go without sending anything before it's disconnected. `None` waits forever, which is the
+    /// default since clients are often idle for long stretches. With heartbeats enabled, echoed heartbeats count as
+    /// traffic, so this can be used to drop clients that are connected but not answering.
+    pub idle_timeout: Option<Duration>,
+    /// How long a client gets to send the rest of a packet after its first byte before it's disconnected.
+    /// `None` waits forever.
+    pub read_timeout: Option<Duration>,
+    /// How long writing a packet to a client may take before it's disconnected. `None` waits forever.
+    pub write_timeout: Option<Duration>,
+    /// How often clients are sent a [`packets::Heartbeat`]. `None` disables heartbeats, which is the default since
+    /// the plugin doesn't echo them yet.
+    pub heartbeat_interval: Option<Duration>,
+    /// How long heartbeats may go unanswered before the client is disconnected (i.e., a client is disconnected after
+    /// missing `heartbeat_timeout / heartbeat_interval` heartbeats in a row).
+    pub heartbeat_timeout: Duration,
+    /// How many chunk requests can be generating at once. Requests beyond this are rejected with
+    /// [`ProtocolErrorKind::ServerBusy`].
+    pub max_pending: usize,
+}
+
+impl Default for ServerParams {
+    fn default() -> Self {
+        Self {
+            addrs: vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 44332)],
+            compression: CompressionAlgo::default(),
+            compression_threshold: 0,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            redact_addresses: false,
+            idle_timeout: None,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            heartbeat_interval: None,
+            heartbeat_timeout: Duration::from_secs(90),
+            max_pending: 1024,
+        }
+    }
 }
 
+/// Callback invoked for every chunk generation request a client sends, see [`Server::set_request_hook`].
+pub type RequestHook = dyn Fn(ConnectionId, &packets::GenerateChunk) + Send + Sync;
+
 pub struct Server {
     net: Networker,
     generators: Arc<Mutex<GeneratorManager>>,
     params: ServerParams,
     running: Arc<AtomicBool>,
+    request_hook: Option<Arc<RequestHook>>,
 }
 
 impl Server {
     pub fn new(params: ServerParams) -> Self {
+        Self::with_pool(params, ThreadPool::default())
+    }
+
+    /// Create a server that generates chunks on the given thread pool, instead of a pool with one thread per CPU.
+    /// This lets embedders cap how many threads are spent on generation (e.g., when running alongside a Minecraft server).
+    pub fn with_pool(params: ServerParams, workers: ThreadPool) -> Self {
         Self {
-            net: Networker::new(params.into()),
-            generators: Mutex::new(GeneratorManager::new()).into(),
+            net: Networker::new(params.clone().into()),
+            generators: Mutex::new(GeneratorManager::new(workers, params.max_pending)).into(),
             params,
             running: Arc::new(AtomicBool::from(false)),
+            request_hook: None,
         }
     }
 
+    /// Stop the server, terminating all connections and stopping the request handler and chunk distributor.
     pub async fn stop(self) -> anyhow::Result<()> {
         self.running.store(false, Ordering::SeqCst);
+
+        // The distributor only checks if it should stop after being woken up, so wake it up.
+        self.generators.lock().await.notify.notify_one();
+
         self.net.stop().await
     }
 
-    pub async fn add_generator<G: ChunkGenerator>(&mut self) {
+    /// Add a generator to the server. Fails if a generator with the same name was already added.
+    pub async fn add_generator<G: ChunkGenerator>(&mut self) -> Result<(), DuplicateFactoryError> {
         if self.running.load(Ordering::SeqCst) {
             panic!("Cannot add new generator while server is running!");
         }
@@ -222,75 +496,68 @@ impl Server {
         self.generators
             .lock()
             .await
-            .add_factory(G::NAME, Box::new(G::factory()));
+            .add_factory(G::NAME, Box::new(G::factory()))
+    }
+
+    /// Set a hook that's called with every chunk generation request before it's submitted for generation.
+    /// Useful for observing requests (e.g., for auditing or quotas) without touching the request handler.
+    /// Like generators, the hook must be set before the server is started.
+    pub fn set_request_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(ConnectionId, &packets::GenerateChunk) + Send + Sync + 'static,
+    {
+        if self.running.load(Ordering::SeqCst) {
+            panic!("Cannot set request hook while server is running!");
+        }
+
+        self.request_hook = Some(Arc::new(hook));
+    }
+
+    /// Remove a generator from the server, returning whether it had been added. Unlike adding generators, this can be
+    /// done while the server is running. Clients can't add the generator anymore afterwards, and requests to generators
+    /// they already added fail as if the generator never existed. Chunks that are already being generated still finish.
+    pub async fn remove_generator(&self, name: &str) -> bool {
+        self.generators.lock().await.remove_factory(name)
+    }
+
+    /// Check if a generator with the given name has been added to this server.
+    pub async fn has_generator(&self, name: &str) -> bool {
+        self.generators.lock().await.has_generator(name)
+    }
+
+    /// Get the names of all generators added to this server.
+    pub async fn generator_names(&self) -> Vec<String> {
+        self.generators.lock().await.generator_names()
     }
 
     /// Start the client request handler thread. This thread handles requests from clients such as
     /// submitting chunks for generation and registering new chunk generators with provided parameters.
     fn start_client_request_handler(&self) {
-        let coarsening = self.params.coarsening;
-
         let running = self.running.clone();
         let net = self.net.clone();
         let manager = self.generators.clone();
+        let request_hook = self.request_hook.clone();
+        let started = Instant::now();
 
         // This thread submits chunks for generation and registers generators at the request of clients.
         tokio::spawn(async move {
+            // Reused between iterations so we don't allocate every time we poll for packets.
+            let mut incoming = Vec::new();
+
             while running.load(Ordering::SeqCst) {
-                // Coarsen the atomic access so the loop can be faster.
-                for _ in 0..coarsening {
-                    for (conn, packet) in net.incoming().await {
-                        match packet {
-                            Ok(packet) => {
-                                if let Some(packet) =
-                                    packet.downcast_ref::<packets::GenerateChunk>()
-                                {
-                                    let request_ident =
-                                        RequestIdent::new(packet.request_id, conn.id());
-
-                                    {
-                                        if let Err(error) = manager
-                                            .lock()
-                                            .await
-                                            .submit_chunk(
-                                                request_ident,
-                                                packet.generator_id,
-                                                packet.args(),
-                                            )
-                                            .await
-                                        {
-                                            log::error!("Request {request_ident:?} failed when submitting chunk for generation: {error}");
-                                        }
-                                    }
-                                }
-
-                                if let Some(packet) = packet.downcast_ref::<packets::AddGenerator>()
-                                {
-                                    let request_ident =
-                                        RequestIdent::new(packet.request_id, conn.id());
-
-                                    if let Ok(generator_id) = manager
-                                        .lock()
-                                        .await
-                                        .register_generator(&packet.name, packet.factory_params())
-                                    {
-                                        conn.send_packet(&packets::ConfirmGeneratorAddition::new(
-                                            request_ident.request_id,
-                                            generator_id,
-                                        ))
-                                        .await
-                                        .unwrap();
-                                    }
-                                }
-                            }
-                            Err(error) => {
-                                conn.send_packet(&ProtocolError::fatal(ProtocolErrorKind::Other {
-                                    details: error.to_string(),
-                                }))
-                                .await
-                                .unwrap();
-                            }
-                        }
+                // Sleep until a client sends us something, instead of polling connections with nothing for us.
+                net.wait_for_incoming().await;
+
+                net.drain_incoming(&mut incoming).await;
+
+                for (conn, packet) in incoming.drain(..) {
+                    // Errors here are almost always the connection going away, which shouldn't bring down the
+                    // handler for everyone else.
+                    if let Err(error) =
+                        handle_packet(&conn, packet, &manager, request_hook.as_deref(), started)
+                            .await
+                    {
+                        log::warn!("Error handling packet from {}: {error}", conn.display_id());
                     }
                 }
             }
@@ -300,32 +567,51 @@ impl Server {
     /// Start the distributor thread for generated chunks. This thread collects chunks from the generator pool and
     /// sends them to their respective clients.
     fn start_chunk_distributor(&self) {
-        let coarsening = self.params.coarsening;
-
         // We don't need this function to be async, doing so would just add needless complexity, so we access the async mutex by blocking.
         let receiver = tokio::task::block_in_place(|| self.generators.blocking_lock().receiver());
         let net = self.net.clone();
         let running = self.running.clone();
+        let redact_addresses = self.params.redact_addresses;
 
         tokio::spawn(async move {
             while running.load(Ordering::SeqCst) {
-                for _ in 0..coarsening {
-                    for completed in receiver.completed().await.collect::<Vec<_>>().into_iter() {
-                        match completed {
-                            GenerationResult::Success(ident, chunk) => {
-                                let packet = ReplyChunk {
+                // The generator pool notifies us whenever it completes a chunk, so we only wake up when there's work to do.
+                receiver.notified().await;
+
+                for completed in receiver.completed().await {
+                    match completed {
+                        GenerationResult::Success(ident, chunk) => {
+                            let packet = ReplyChunk {
+                                request_id: ident.into(),
+                                chunk,
+                            };
+
+                            if let Some(conn) = net.connection(ident.into()).await {
+                                send_or_log(&conn, &packet).await;
+                            }
+                        }
+                        GenerationResult::Delta(ident, packet) => {
+                            if let Some(conn) = net.connection(ident.into()).await {
+                                send_or_log(&conn, &packet).await;
+                            }
+                        }
+                        GenerationResult::Failure(ident, error) => {
+                            log::error!(
+                                "Request {} from {} failed: {error}",
+                                ident.request_ident.request_id,
+                                ident.request_ident.connection_id.display(redact_addresses)
+                            );
+
+                            // Let the client know that the request failed, otherwise it'll be waiting for a reply forever.
+                            let packet =
+                                ProtocolError::gentle(ProtocolErrorKind::ChunkGenerationFailure {
+                                    generator_id: ident.into(),
                                     request_id: ident.into(),
-                                    chunk,
-                                };
+                                    details: error.to_string(),
+                                });
 
-                                if let Some(conn) = net.connection(ident.into()).await {
-                                    conn.send_packet(&packet).await.unwrap();
-                                }
-                            }
-                            GenerationResult::Failure(ident, error) => {
-                                log::error!("Request {ident:?} failed: {error}");
-                                // let net_error = ProtocolErrorKind::ChunkGenerationFailure { generator_id: , request_id: () };
-                                // let packet = ProtocolError::gentle()
+                            if let Some(conn) = net.connection(ident.into()).await {
+                                send_or_log(&conn, &packet).await;
                             }
                         }
                     }
@@ -341,9 +627,196 @@ impl Server {
 
         self.running.store(true, Ordering::SeqCst);
 
+        // Nobody's around to receive the chunks a closed connection asked for, so don't waste time generating them.
+        let in_flight = self.generators.lock().await.in_flight.clone();
+        let redact_addresses = self.params.redact_addresses;
+        self.net.set_close_hook(move |connection_id| {
+            let cancelled = cancel_connection(&in_flight, connection_id);
+            if cancelled > 0 {
+                log::info!(
+                    "Cancelled {cancelled} requests from {}, which closed",
+                    connection_id.display(redact_addresses)
+                );
+            }
+        });
+
         self.net.run().await.unwrap();
 
         self.start_client_request_handler();
         self.start_chunk_distributor();
     }
 }
+
+/// Handle a packet sent to the server by `conn`.
+async fn handle_packet(
+    conn: &Connection,
+    packet: anyhow::Result<Box<dyn packets::DowncastPacket>>,
+    manager: &Mutex<GeneratorManager>,
+    request_hook: Option<&RequestHook>,
+    started: Instant,
+) -> anyhow::Result<()> {
+    match packet {
+        Ok(packet) => {
+            if let Some(packet) = packet.downcast_ref::<packets::GenerateChunk>() {
+                submit_chunk(conn, packet, None, manager, request_hook).await?;
+            }
+
+            if let Some(packet) = packet.downcast_ref::<packets::GenerateChunkDelta>() {
+                let request = packets::GenerateChunk {
+                    request_id: packet.request_id,
+                    generator_id: packet.generator_id,
+                    pos: packet.pos,
+                };
+
+                submit_chunk(
+                    conn,
+                    &request,
+                    Some(packet.base_generator_id),
+                    manager,
+                    request_hook,
+                )
+                .await?;
+            }
+
+            if let Some(packet) = packet.downcast_ref::<packets::HealthCheck>() {
+                let active_requests = manager.lock().await.active_requests();
+
+                conn.send_packet(&packets::HealthOk {
+                    nonce: packet.nonce,
+                    uptime_ms: started.elapsed().as_millis() as u64,
+                    active_requests: active_requests as u32,
+                })
+                .await?;
+            }
+
+            if let Some(packet) = packet.downcast_ref::<packets::RequestGenerators>() {
+                let mut generators = manager.lock().await.generator_names();
+                generators.sort_unstable();
+
+                conn.send_packet(&packets::ListGenerators {
+                    request_id: packet.request_id,
+                    generators,
+                })
+                .await?;
+            }
+
+            if let Some(packet) = packet.downcast_ref::<packets::CancelRequest>() {
+                let request_ident = RequestIdent::new(packet.request_id, conn.id());
+
+                if manager.lock().await.cancel(request_ident) {
+                    conn.send_packet(&packets::AckRequest {
+                        request_id: packet.request_id,
+                        info: "cancelled".to_string(),
+                    })
+                    .await?;
+                } else {
+                    conn.send_packet(&ProtocolError::gentle(ProtocolErrorKind::Other {
+                        details: format!(
+                            "request {} can't be cancelled, it isn't being generated",
+                            packet.request_id
+                        ),
+                    }))
+                    .await?;
+                }
+            }
+
+            if let Some(packet) = packet.downcast_ref::<packets::AddGenerator>() {
+                let request_ident = RequestIdent::new(packet.request_id, conn.id());
+
+                if let Err(kind) = packet.validate() {
+                    conn.send_packet(&ProtocolError::gentle(kind)).await?;
+                    return Ok(());
+                }
+
+                let registered = manager
+                    .lock()
+                    .await
+                    .register_generator(&packet.name, packet.factory_params());
+
+                match registered {
+                    Ok(generator_id) => {
+                        conn.send_packet(&packets::ConfirmGeneratorAddition::new(
+                            request_ident.request_id,
+                            generator_id,
+                        ))
+                        .await?;
+                    }
+                    Err(UnknownFactoryError(name)) => {
+                        conn.send_packet(&ProtocolError::gentle(
+                            ProtocolErrorKind::UnknownGenerator {
+                                request_id: request_ident.request_id,
+                                name: name.to_string(),
+                            },
+                        ))
+                        .await?;
+                    }
+                }
+            }
+        }
+        Err(error) => {
+            conn.send_packet(&ProtocolError::fatal(ProtocolErrorKind::Other {
+                details: error.to_string(),
+            }))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Submit a chunk requested by `conn` for generation, diffed against the chunk from `base_generator_id` if given.
+async fn submit_chunk(
+    conn: &Connection,
+    packet: &packets::GenerateChunk,
+    base_generator_id: Option<GeneratorId>,
+    manager: &Mutex<GeneratorManager>,
+    request_hook: Option<&RequestHook>,
+) -> anyhow::Result<()> {
+    let request_ident = RequestIdent::new(packet.request_id, conn.id());
+
+    if let Some(hook) = request_hook {
+        hook(conn.id(), packet);
+    }
+
+    let submitted = manager
+        .lock()
+        .await
+        .submit_chunk(
+            request_ident,
+            packet.generator_id,
+            base_generator_id,
+            packet.args(),
+        )
+        .await;
+
+    match submitted {
+        Ok(()) => (),
+        Err(ManagerSubmitError::Busy(_)) => {
+            // Not really an error on our part, the client can just try again later.
+            conn.send_packet(&ProtocolError::server_busy(
+                request_ident.request_id,
+                BUSY_RETRY_AFTER,
+            ))
+            .await?;
+        }
+        Err(ManagerSubmitError::GeneratorNotFound(generator_id)) => {
+            // Without this the client would wait for a reply that's never coming.
+            conn.send_packet(&ProtocolError::gentle(
+                ProtocolErrorKind::GeneratorNotFound {
+                    generator_id,
+                    request_id: request_ident.request_id,
+                },
+            ))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `packet` to `conn`, logging instead of panicking if that fails (e.g., because the client disconnected).
+async fn send_or_log<P: packets::Packet>(conn: &Connection, packet: &P) {
+    if let Err(error) = conn.send_packet(packet).await {
+        log::warn!("Error sending packet to {}: {error}", conn.display_id());
+    }
+}
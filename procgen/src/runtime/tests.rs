@@ -1,28 +1,41 @@
 use std::{
     io::{Read, Write},
-    net::{SocketAddrV4, TcpStream},
+    marker::PhantomData,
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use rand::Rng;
+use threadpool::ThreadPool;
+use tokio::io::AsyncReadExt;
 use volume::Volume;
 
 use crate::{
-    generation::{ChunkGenerator, GeneratorFactory},
+    generation::{chunk_rng, ChunkGenerator, GeneratorFactory},
     runtime::{
+        client::{Client, ClientError},
         net::packets::ProtocolErrorKind,
         server::{Server, ServerParams},
     },
 };
 
 use common::{
-    generation::{FactoryParameters, GenerationArgs},
-    BlockId, Chunk, Spaces,
+    generation::{FactoryParameters, GenerationArgs, Parameters},
+    BlockId, Chunk, ConnectionId, GeneratorId, Spaces,
 };
 
 use super::net::{
-    packets::{self, GenerateChunk, Packet, ReplyChunk},
-    Header, Networker, Params,
+    packets::{
+        self, AddGeneratorBuilder, GenerateChunk, Heartbeat, Packet, PacketBuffer, ProtocolError,
+        ReplyChunk,
+    },
+    CompressionAlgo, Compressor, CompressorError, Connection, Header, HeaderError, Networker,
+    Params, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
 
 struct MockClient {
@@ -30,7 +43,7 @@ struct MockClient {
 }
 
 impl MockClient {
-    fn new(addr: SocketAddrV4) -> Self {
+    fn new(addr: SocketAddr) -> Self {
         Self {
             stream: TcpStream::connect(addr).unwrap(),
         }
@@ -62,7 +75,25 @@ impl MockClient {
     }
 
     fn read_packet<P: Packet>(&mut self) -> anyhow::Result<P> {
-        let header = Header::sync_read(&mut self.stream)?;
+        Ok(self.read_buffer()?.to_packet::<P>()?)
+    }
+
+    /// Read the next packet that isn't a heartbeat, echoing any heartbeats received before it like a real client would.
+    fn read_buffer(&mut self) -> anyhow::Result<PacketBuffer> {
+        loop {
+            let buf = self.read_any_buffer()?;
+
+            if buf.id() != Heartbeat::ID {
+                return Ok(buf);
+            }
+
+            let heartbeat = buf.to_packet::<Heartbeat>()?;
+            self.send_packet(&heartbeat)?;
+        }
+    }
+
+    fn read_any_buffer(&mut self) -> anyhow::Result<PacketBuffer> {
+        let header = Header::sync_read(&mut self.stream, u32::MAX)?;
         let mut compressed_buf = vec![0u8; header.compressed_len as usize];
 
         self.stream.read_exact(&mut compressed_buf)?;
@@ -75,11 +106,60 @@ impl MockClient {
             buf
         };
 
-        let packet = bincode::deserialize::<P>(&decompressed_buf[2..])?;
-        Ok(packet)
+        Ok(PacketBuffer::from_vec(decompressed_buf)?)
     }
 }
 
+fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed_buf = Vec::<u8>::new();
+    let mut compressor = ZlibEncoder::new(&mut compressed_buf, Compression::best());
+    compressor.write_all(data).unwrap();
+    compressor.finish().unwrap();
+
+    compressed_buf
+}
+
+fn frame(header: Header, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    header.sync_write(&mut buf).unwrap();
+    buf.extend_from_slice(body);
+
+    buf
+}
+
+/// The [`packets::AddGenerator`] request most tests use to add `G`.
+fn add_generator_request<G: ChunkGenerator>() -> AddGeneratorBuilder {
+    packets::AddGenerator::builder()
+        .request_id(500.into())
+        .name(G::NAME)
+        .default_id(21.into())
+}
+
+/// Add `G` to `server` and start it, then connect a client to it on `port` and have the client add `G` like most
+/// tests do (see [`add_generator_request`]). Returns the client and the ID of the generator it added.
+async fn start_with_generator<G: ChunkGenerator>(
+    server: &mut Server,
+    port: u16,
+) -> (MockClient, GeneratorId) {
+    server.add_generator::<G>().await.unwrap();
+    server.run().await;
+
+    let mut client = MockClient::new(SocketAddr::from(([127, 0, 0, 1], port)));
+
+    client
+        .send_packet(&add_generator_request::<G>().build())
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let packet = client
+        .read_packet::<packets::ConfirmGeneratorAddition>()
+        .unwrap();
+    assert_eq!(packet.request_id, 500.into());
+
+    (client, packet.generator_id)
+}
+
 struct MockGenFactory;
 
 impl GeneratorFactory for MockGenFactory {
@@ -122,28 +202,446 @@ impl ChunkGenerator for MockGenerator {
     }
 }
 
+/// What a [`TestGenerator`] does when asked to generate a chunk.
+enum Behaviour {
+    /// Generate the same chunk as [`MockGenerator`].
+    Mock,
+    /// Always fail.
+    Fail,
+    /// Generate the chunk one chunk over from where it was requested.
+    Misplace,
+}
+
+/// Chunks currently being generated by generators with [`TestConfig::TRACK_ACTIVE`] set, and the most there have been
+/// at once.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static MAX_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Configures a [`TestGenerator`]. Every configuration is its own generator, so each one needs a unique name.
+trait TestConfig: Send + Sync + 'static {
+    const NAME: &'static str;
+    const BEHAVIOUR: Behaviour = Behaviour::Mock;
+    /// How long generating a chunk takes.
+    const DELAY: Duration = Duration::ZERO;
+    /// Count chunks being generated in [`ACTIVE`] and [`MAX_ACTIVE`].
+    const TRACK_ACTIVE: bool = false;
+}
+
+struct TestGenFactory<C>(PhantomData<C>);
+
+impl<C: TestConfig> GeneratorFactory for TestGenFactory<C> {
+    type Generator = TestGenerator<C>;
+
+    fn create(&self, params: FactoryParameters<'_>) -> Self::Generator {
+        TestGenerator(MockGenFactory.create(params), PhantomData)
+    }
+}
+
+/// Wraps a [`MockGenerator`] to make it slow or misbehave as configured by `C`, for testing how the server copes.
+struct TestGenerator<C>(MockGenerator, PhantomData<C>);
+
+impl<C: TestConfig> ChunkGenerator for TestGenerator<C> {
+    const NAME: &'static str = C::NAME;
+
+    type Factory = TestGenFactory<C>;
+
+    fn generate(&self, args: &GenerationArgs) -> anyhow::Result<Chunk> {
+        if C::TRACK_ACTIVE {
+            let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_ACTIVE.fetch_max(active, Ordering::SeqCst);
+        }
+
+        std::thread::sleep(C::DELAY);
+        let chunk = match C::BEHAVIOUR {
+            Behaviour::Mock => self.0.generate(args),
+            Behaviour::Fail => Err(anyhow::anyhow!("this generator always fails")),
+            Behaviour::Misplace => Ok(Chunk::new(
+                self.0.default_id,
+                args.pos + na::vector![1, 0],
+                self.0.min_height,
+                self.0.max_height,
+            )),
+        };
+
+        if C::TRACK_ACTIVE {
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+        chunk
+    }
+
+    fn factory() -> Self::Factory {
+        TestGenFactory(PhantomData)
+    }
+}
+
+struct Failing;
+
+impl TestConfig for Failing {
+    const NAME: &'static str = "FAILING_GENERATOR";
+    const BEHAVIOUR: Behaviour = Behaviour::Fail;
+}
+
+type FailingGenerator = TestGenerator<Failing>;
+
+struct Misplaced;
+
+impl TestConfig for Misplaced {
+    const NAME: &'static str = "MISPLACED_GENERATOR";
+    const BEHAVIOUR: Behaviour = Behaviour::Misplace;
+}
+
+type MisplacedGenerator = TestGenerator<Misplaced>;
+
+/// Takes a while to generate and keeps track of how many chunks are being generated at the same time.
+struct Slow;
+
+impl TestConfig for Slow {
+    const NAME: &'static str = "SLOW_GENERATOR";
+    const DELAY: Duration = Duration::from_millis(50);
+    const TRACK_ACTIVE: bool = true;
+}
+
+type SlowGenerator = TestGenerator<Slow>;
+
+/// Sleeps for a while before generating, so requests pile up.
+struct Sleepy;
+
+impl TestConfig for Sleepy {
+    const NAME: &'static str = "SLEEPY_GENERATOR";
+    const DELAY: Duration = Duration::from_millis(250);
+}
+
+type SleepyGenerator = TestGenerator<Sleepy>;
+
+struct SeededGenFactory;
+
+impl GeneratorFactory for SeededGenFactory {
+    type Generator = SeededGenerator;
+
+    fn create(&self, params: FactoryParameters<'_>) -> Self::Generator {
+        SeededGenerator {
+            seed: params.params.seed,
+            min_height: params.min_height,
+            max_height: params.max_height,
+            default_id: params.default,
+        }
+    }
+}
+
+/// Scatters random blocks around the chunk, using an RNG from [`chunk_rng`].
+struct SeededGenerator {
+    seed: u64,
+    min_height: i32,
+    max_height: i32,
+    default_id: BlockId,
+}
+
+impl ChunkGenerator for SeededGenerator {
+    const NAME: &'static str = "SEEDED_GENERATOR";
+
+    type Factory = SeededGenFactory;
+
+    fn generate(&self, args: &GenerationArgs) -> anyhow::Result<Chunk> {
+        let mut chunk = Chunk::new(self.default_id, args.pos, self.min_height, self.max_height);
+        let mut rng = chunk_rng(self.seed, args);
+
+        for x in 0..16 {
+            for z in 0..16 {
+                let y = rng.gen_range(self.min_height..self.max_height);
+                chunk.set(Spaces::Cs([x, y, z]), rng.gen_range(1..100u32).into());
+            }
+        }
+
+        Ok(chunk)
+    }
+
+    fn factory() -> Self::Factory {
+        SeededGenFactory
+    }
+}
+
+#[test]
+fn seeded_generation_is_deterministic() {
+    let generate = |seed: u64, pos: [i32; 2]| {
+        let params = Parameters::new().with_seed(seed);
+        let generator = SeededGenerator::factory().create(FactoryParameters {
+            max_height: 320,
+            min_height: -64,
+            default: 0.into(),
+            params: &params,
+        });
+
+        generator
+            .generate(&GenerationArgs { pos: pos.into() })
+            .unwrap()
+    };
+
+    assert!(generate(42, [3, -7]) == generate(42, [3, -7]));
+    assert!(generate(42, [3, -7]) != generate(43, [3, -7]));
+    assert!(generate(42, [3, -7]) != generate(42, [-7, 3]));
+}
+
+#[test]
+fn header_version_round_trip() {
+    let mut buf = Vec::new();
+    Header::new(20, 40).sync_write(&mut buf).unwrap();
+
+    assert_eq!(buf[0], PROTOCOL_VERSION);
+
+    let header = Header::sync_read(&mut &buf[..], u32::MAX).unwrap();
+    assert_eq!(header.compressed_len, 20);
+    assert_eq!(header.decompressed_len, 40);
+}
+
+#[tokio::test]
+async fn legacy_header_read_as_zlib() {
+    // Version 1 headers have no algorithm byte, just the two lengths.
+    let mut buf = vec![LEGACY_PROTOCOL_VERSION];
+    buf.extend_from_slice(&20u32.to_be_bytes());
+    buf.extend_from_slice(&40u32.to_be_bytes());
+
+    let zlib = CompressionAlgo::Zlib(Compression::best()).id();
+
+    let header = Header::sync_read(&mut &buf[..], u32::MAX).unwrap();
+    assert_eq!(header.algorithm, zlib);
+    assert_eq!(header.compressed_len, 20);
+    assert_eq!(header.decompressed_len, 40);
+
+    let header = Header::read(&mut &buf[..], u32::MAX).await.unwrap();
+    assert_eq!(header.algorithm, zlib);
+    assert_eq!(header.compressed_len, 20);
+    assert_eq!(header.decompressed_len, 40);
+
+    // Whole packets from version 1 peers can be read too.
+    let packet = GenerateChunk {
+        request_id: 1.into(),
+        generator_id: 2.into(),
+        pos: na::vector![3, 4],
+    };
+    let raw = packet.to_bincode().unwrap();
+    let compressed = zlib_compress(raw.as_ref());
+
+    let mut legacy = vec![LEGACY_PROTOCOL_VERSION];
+    legacy.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    legacy.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+    legacy.extend_from_slice(&compressed);
+
+    let read = Compressor::new(CompressionAlgo::default())
+        .read(&mut &legacy[..])
+        .await
+        .unwrap();
+    assert_eq!(read.to_packet::<GenerateChunk>().unwrap(), packet);
+}
+
+#[tokio::test]
+async fn decompression_bomb_rejected() {
+    let raw = PacketBuffer::from_vec(vec![0u8; 1024 * 1024]).unwrap();
+
+    for algorithm in [
+        CompressionAlgo::Zlib(Compression::best()),
+        CompressionAlgo::Zstd(3),
+        CompressionAlgo::Lz4,
+    ] {
+        let mut buf = Vec::new();
+        Compressor::new(algorithm)
+            .write(&raw, &mut buf)
+            .await
+            .unwrap();
+
+        // Claim the packet is tiny, the reader must not decompress all of it to find out it isn't.
+        let mut header = Header::sync_read(&mut &buf[..], u32::MAX).unwrap();
+        header.decompressed_len = 16;
+        let body = buf[buf.len() - header.compressed_len as usize..].to_vec();
+
+        let read = Compressor::new(algorithm)
+            .read(&mut &frame(header, &body)[..])
+            .await;
+        assert!(
+            matches!(
+                read,
+                Err(CompressorError::Decompress(_) | CompressorError::LengthMismatch { .. })
+            ),
+            "{algorithm:?} decompressed past the header's length"
+        );
+    }
+}
+
+#[tokio::test]
+async fn header_wrong_version_rejected() {
+    let mut buf = Vec::new();
+    Header::new(20, 40).sync_write(&mut buf).unwrap();
+    buf[0] = PROTOCOL_VERSION.wrapping_add(1);
+
+    assert!(matches!(
+        Header::sync_read(&mut &buf[..], u32::MAX),
+        Err(HeaderError::MismatchedVersion {
+            expected: PROTOCOL_VERSION,
+            ..
+        })
+    ));
+
+    assert!(matches!(
+        Header::read(&mut &buf[..], u32::MAX).await,
+        Err(HeaderError::MismatchedVersion {
+            expected: PROTOCOL_VERSION,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn compression_algorithms_round_trip() {
+    let packet = GenerateChunk {
+        request_id: 1.into(),
+        generator_id: 2.into(),
+        pos: na::vector![3, 4],
+    }
+    .to_bincode()
+    .unwrap();
+
+    let algorithms = [
+        CompressionAlgo::Zlib(Compression::fast()),
+        CompressionAlgo::Zstd(3),
+        CompressionAlgo::Lz4,
+        CompressionAlgo::None,
+    ];
+
+    // Packets are read with the algorithm in their header, not the reader's own algorithm.
+    let reader = Compressor::new(CompressionAlgo::default());
+
+    for algorithm in algorithms {
+        let mut buf = Vec::new();
+        Compressor::new(algorithm)
+            .write(&packet, &mut buf)
+            .await
+            .unwrap();
+
+        assert_eq!(buf[1], algorithm.id());
+        assert_eq!(reader.read(&mut &buf[..]).await.unwrap(), packet);
+    }
+
+    let mut unknown = frame(
+        Header::new(packet.len() as u32, packet.len() as u32).with_algorithm(CompressionAlgo::None),
+        packet.as_ref(),
+    );
+    unknown[1] = 200;
+    assert!(matches!(
+        reader.read(&mut &unknown[..]).await,
+        Err(CompressorError::UnknownAlgorithm(200))
+    ));
+}
+
+#[tokio::test]
+async fn small_packets_sent_uncompressed() {
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best())).with_threshold(64);
+
+    let small = packets::ConfirmGeneratorAddition::new(1.into(), 2.into())
+        .to_bincode()
+        .unwrap();
+    let large = PacketBuffer::from_vec(vec![0u8; 1024]).unwrap();
+    assert!(small.len() < 64);
+
+    let mut buf = Vec::new();
+    compressor.write(&small, &mut buf).await.unwrap();
+
+    let header = Header::sync_read(&mut &buf[..], u32::MAX).unwrap();
+    assert_eq!(header.algorithm, CompressionAlgo::None.id());
+    assert_eq!(header.compressed_len, header.decompressed_len);
+    assert_eq!(compressor.read(&mut &buf[..]).await.unwrap(), small);
+
+    let mut buf = Vec::new();
+    compressor.write(&large, &mut buf).await.unwrap();
+
+    let header = Header::sync_read(&mut &buf[..], u32::MAX).unwrap();
+    assert_eq!(
+        header.algorithm,
+        CompressionAlgo::Zlib(Compression::best()).id()
+    );
+    assert!(header.compressed_len < header.decompressed_len);
+    assert_eq!(compressor.read(&mut &buf[..]).await.unwrap(), large);
+}
+
+#[tokio::test]
+async fn compressor_error_variants() {
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()));
+    let packet = GenerateChunk {
+        request_id: 1.into(),
+        generator_id: 2.into(),
+        pos: na::vector![3, 4],
+    }
+    .to_bincode()
+    .unwrap();
+    let compressed = zlib_compress(packet.as_ref());
+
+    // A valid packet round-trips.
+    let valid = frame(
+        Header::new(compressed.len() as u32, packet.len() as u32),
+        &compressed,
+    );
+    assert_eq!(compressor.read(&mut &valid[..]).await.unwrap(), packet);
+
+    // The stream closing before a header is a clean EOF.
+    let mut empty: &[u8] = &[];
+    assert!(matches!(
+        compressor.read(&mut empty).await,
+        Err(CompressorError::Eof)
+    ));
+
+    // The stream closing in the middle of a packet is an IO error.
+    let mut truncated = &valid[..valid.len() - 1];
+    assert!(matches!(
+        compressor.read(&mut truncated).await,
+        Err(CompressorError::Io(_))
+    ));
+
+    // Garbage that isn't zlib can't be decompressed.
+    let garbage = [0xFFu8; 16];
+    let corrupt = frame(Header::new(garbage.len() as u32, 16), &garbage);
+    assert!(matches!(
+        compressor.read(&mut &corrupt[..]).await,
+        Err(CompressorError::Decompress(_))
+    ));
+
+    // The header must agree with the decompressed packet's length.
+    let mismatched = frame(
+        Header::new(compressed.len() as u32, packet.len() as u32 + 1),
+        &compressed,
+    );
+    assert!(matches!(
+        compressor.read(&mut &mismatched[..]).await,
+        Err(CompressorError::LengthMismatch { .. })
+    ));
+
+    let mut wrong_version = valid.clone();
+    wrong_version[0] = PROTOCOL_VERSION.wrapping_add(1);
+    assert!(matches!(
+        compressor.read(&mut &wrong_version[..]).await,
+        Err(CompressorError::MismatchedVersion { .. })
+    ));
+}
+
 // FIXME: currently these tests can lock up and do a bunch of dumb stuff (probably) because we're mixing sync and async code here (our mock client is sync, but everything else is async)
 // try making the tests a little more stable and also make sure that it's the test's fault and not some kind of deadlock in the actual code itself.
 #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
 async fn networker_recv() {
     let params = Params {
-        addr: "0.0.0.0:33445".parse().unwrap(),
-        compression: Compression::best(),
+        addrs: vec!["0.0.0.0:33445".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
     };
 
     let mut networker = Networker::new(params);
 
     networker.run().await.unwrap();
 
-    let mut client = MockClient::new("127.0.0.1:33445".parse::<SocketAddrV4>().unwrap());
+    let mut client = MockClient::new("127.0.0.1:33445".parse::<SocketAddr>().unwrap());
 
-    let packet = packets::AddGenerator {
-        request_id: 42.into(),
-        name: "hello!!!".to_string(),
-        min_height: -64,
-        max_height: 320,
-        default_id: 0.into(),
-    };
+    let packet = packets::AddGenerator::builder()
+        .request_id(42.into())
+        .name("hello!!!")
+        .param("octaves", 4i64)
+        .param("scale", 0.5)
+        .build();
 
     client.send_packet(&packet).unwrap();
 
@@ -159,171 +657,1542 @@ async fn networker_recv() {
             .unwrap();
         assert_eq!(packet.name, "hello!!!");
         assert_eq!(packet.request_id, 42.into());
+        assert_eq!(packet.params.get_int("octaves"), Ok(4));
     }
 
     networker.stop().await.unwrap();
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
-async fn networker_send() {
+async fn networker_drain_incoming() {
     let params = Params {
-        addr: "0.0.0.0:33446".parse().unwrap(),
-        compression: Compression::best(),
+        addrs: vec!["0.0.0.0:33447".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
     };
 
     let mut networker = Networker::new(params);
     networker.run().await.unwrap();
 
-    let mut client = MockClient::new("127.0.0.1:33446".parse::<SocketAddrV4>().unwrap());
+    let mut client = MockClient::new("127.0.0.1:33447".parse::<SocketAddr>().unwrap());
 
-    let generate_chunk_packet = GenerateChunk {
-        request_id: 560.into(),
+    let packet = GenerateChunk {
+        request_id: 77.into(),
         generator_id: 4.into(),
-        pos: na::vector![-6, 2],
+        pos: na::vector![1, 2],
     };
 
-    client.send_packet(&generate_chunk_packet).unwrap();
+    let mut buf = Vec::new();
+
+    for _ in 0..2 {
+        client.send_packet(&packet).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let expected = networker
+            .incoming()
+            .await
+            .map(|(conn, p)| {
+                let p = p.unwrap();
+                (
+                    conn.id(),
+                    p.downcast_ref::<GenerateChunk>().unwrap().request_id,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        client.send_packet(&packet).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        buf.clear();
+        networker.drain_incoming(&mut buf).await;
+
+        let drained = buf
+            .iter()
+            .map(|(conn, p)| {
+                let p = p.as_ref().unwrap();
+                (
+                    conn.id(),
+                    p.downcast_ref::<GenerateChunk>().unwrap().request_id,
+                )
+            })
+            .collect::<Vec<_>>();
 
-    tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(expected.len(), 1);
+        assert_eq!(drained, expected);
+    }
 
-    let mut chunk = Chunk::new(77.into(), na::vector![-6, 2], -64, 320);
-    chunk.set(Spaces::Cs([10i32, 120, 8]), 80.into());
-    chunk.set(Spaces::Cs([6i32, -20, 9]), 92.into());
+    networker.stop().await.unwrap();
+}
 
-    for (conn, raw_packet) in networker.incoming().await {
-        let packet = raw_packet
-            .as_ref()
-            .unwrap()
-            .downcast_ref::<GenerateChunk>()
-            .unwrap();
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn networker_distinct_connection_ids() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33448".parse().unwrap()],
+        ..Default::default()
+    };
 
-        let chunk_packet = ReplyChunk {
-            request_id: packet.request_id,
-            chunk: chunk.clone(),
-        };
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
 
-        conn.send_packet(&chunk_packet).await.unwrap();
-    }
+    let clients = [
+        MockClient::new("127.0.0.1:33448".parse().unwrap()),
+        MockClient::new("127.0.0.1:33448".parse().unwrap()),
+    ];
 
-    tokio::time::sleep(Duration::from_millis(500)).await;
+    tokio::time::sleep(Duration::from_millis(250)).await;
 
-    let received_packet = client.read_packet::<ReplyChunk>().unwrap();
+    let mut ids = Vec::new();
+    for client in clients.iter() {
+        let addr = client.stream.local_addr().unwrap();
 
-    assert_eq!(received_packet.request_id, 560.into());
-    assert_eq!(received_packet.chunk, chunk);
+        let conns = networker.connections_from(addr).await;
+        assert_eq!(conns.len(), 1);
+
+        let id = conns[0].id();
+        assert_eq!(id.addr(), addr);
+        assert!(networker.connection(id).await.is_some());
+        ids.push(id);
+    }
+
+    // Every accepted connection gets its own sequence number, even if the peer address is reused.
+    assert_ne!(ids[0].seq(), ids[1].seq());
 
     networker.stop().await.unwrap();
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
-async fn end_to_end_server_test() {
+async fn wrong_version_terminates_connection() {
     let params = ServerParams {
-        addr: "0.0.0.0:33443".parse().unwrap(),
-        compression: Compression::best(),
-        coarsening: 100,
+        addrs: vec!["0.0.0.0:33485".parse().unwrap()],
+        ..Default::default()
     };
 
     let mut server = Server::new(params);
-
-    server.add_generator::<MockGenerator>().await;
-
     server.run().await;
 
-    let mut client = MockClient::new("127.0.0.1:33443".parse().unwrap());
-
+    let mut client = MockClient::new("127.0.0.1:33485".parse().unwrap());
     client
-        .send_packet(&packets::AddGenerator {
-            request_id: 500.into(),
-            name: MockGenerator::NAME.to_string(),
-            min_height: -64,
-            max_height: 320,
-            default_id: 21.into(),
-        })
+        .stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
         .unwrap();
 
-    tokio::time::sleep(Duration::from_millis(250)).await;
+    // Not a version we know, so the server can't tell where this packet (or any after it) ends.
+    let garbage = PROTOCOL_VERSION.wrapping_add(100);
+    // Only the version byte, anything left unread could make the server's close reset the connection.
+    client.stream.write_all(&[garbage]).unwrap();
 
-    let generator_id = {
-        let packet = client
-            .read_packet::<packets::ConfirmGeneratorAddition>()
-            .unwrap();
-        assert_eq!(packet.request_id, 500.into());
-        packet.generator_id
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+    assert!(packet.fatal);
+    let message = packet.kind.message();
+    assert!(message.contains(&format!("expected {PROTOCOL_VERSION}")));
+    assert!(message.contains(&format!("got {garbage}")));
+
+    let mut buf = [0u8; 1];
+    assert_eq!(client.stream.read(&mut buf).unwrap(), 0);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn disconnected_peer_forgotten() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33484".parse().unwrap()],
+        ..Default::default()
     };
 
-    client
-        .send_packet(&packets::GenerateChunk {
-            request_id: 420.into(),
-            generator_id,
-            pos: na::vector![6i32, 4],
-        })
-        .unwrap();
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let client = MockClient::new("127.0.0.1:33484".parse().unwrap());
+    let addr = client.stream.local_addr().unwrap();
 
     tokio::time::sleep(Duration::from_millis(250)).await;
 
-    let packet = client.read_packet::<packets::ReplyChunk>().unwrap();
-    assert_eq!(packet.request_id, 420.into());
+    let id = networker.connections_from(addr).await[0].id();
+    assert!(networker.connection(id).await.is_some());
 
-    for x in 0..16 {
-        for z in 0..16 {
-            assert_eq!(
-                packet.chunk.get(Spaces::Cs([x, -64i32, z])),
-                Some(&BlockId::new(80))
-            );
-        }
-    }
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(250)).await;
 
-    server.stop().await.unwrap();
+    assert!(networker.connection(id).await.is_none());
+    assert!(networker.connections_from(addr).await.is_empty());
+
+    networker.stop().await.unwrap();
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
-async fn server_stopping() {
-    let params = ServerParams {
-        addr: "0.0.0.0:33463".parse().unwrap(),
-        compression: Compression::best(),
-        coarsening: 100,
+async fn writing_to_closed_peer_drops_connection() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33487".parse().unwrap()],
+        ..Default::default()
     };
 
-    let mut server = Server::new(params);
-    server.add_generator::<MockGenerator>().await;
-    server.run().await;
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
 
-    let t1 = std::thread::spawn(|| {
-        let mut client1 = MockClient::new("127.0.0.1:33463".parse().unwrap());
+    let client = MockClient::new("127.0.0.1:33487".parse().unwrap());
+    let addr = client.stream.local_addr().unwrap();
 
-        client1
-            .send_packet(&packets::AddGenerator {
-                request_id: 101.into(),
-                name: "MOCK_GENERATOR".to_string(),
-                min_height: -64,
-                max_height: 320,
-                default_id: 21.into(),
-            })
-            .unwrap();
+    tokio::time::sleep(Duration::from_millis(250)).await;
 
-        std::thread::sleep(Duration::from_millis(250));
+    let conn = networker.connections_from(addr).await[0].clone();
 
-        let packet1 = client1
-            .read_packet::<packets::ConfirmGeneratorAddition>()
-            .unwrap();
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(250)).await;
 
-        assert_eq!(packet1.request_id, 101.into());
+    // The first few writes may still land in the socket's buffer, but once the peer resets the connection the
+    // writer should give up on it, after which nothing can be queued anymore.
+    let mut dropped = false;
+    for _ in 0..100 {
+        let packet = GenerateChunk {
+            request_id: 1.into(),
+            generator_id: 2.into(),
+            pos: na::vector![3, 4],
+        };
 
-        client1
-    });
+        if conn.send_packet(&packet).await.is_err() {
+            dropped = true;
+            break;
+        }
 
-    let t2 = std::thread::spawn(|| {
-        let mut client2 = MockClient::new("127.0.0.1:33463".parse().unwrap());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
 
-        client2
-            .send_packet(&packets::AddGenerator {
-                request_id: 202.into(),
-                name: "MOCK_GENERATOR".to_string(),
-                min_height: -128,
-                max_height: 320,
-                default_id: 42.into(),
-            })
-            .unwrap();
+    assert!(dropped);
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn networker_listens_on_every_address() {
+    let params = Params {
+        addrs: vec![
+            "127.0.0.1:33473".parse().unwrap(),
+            "127.0.0.1:33474".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut clients = [
+        MockClient::new("127.0.0.1:33473".parse().unwrap()),
+        MockClient::new("127.0.0.1:33474".parse().unwrap()),
+    ];
+
+    for (request_id, client) in clients.iter_mut().enumerate() {
+        client
+            .send_packet(&GenerateChunk {
+                request_id: (request_id as u32).into(),
+                generator_id: 1.into(),
+                pos: na::vector![0, 0],
+            })
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    // Packets from both listeners end up in the same place.
+    let mut request_ids = networker
+        .incoming()
+        .await
+        .map(|(_, packet)| {
+            packet
+                .unwrap()
+                .downcast_ref::<GenerateChunk>()
+                .unwrap()
+                .request_id
+                .0
+        })
+        .collect::<Vec<_>>();
+    request_ids.sort_unstable();
+
+    assert_eq!(request_ids, [0, 1]);
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn stalled_body_drops_connection() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33449".parse().unwrap()],
+        read_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:33449").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // Send a header promising a body, then never send the body.
+    Header::new(100, 100).sync_write(&mut stream).unwrap();
+
+    let start = std::time::Instant::now();
+
+    // The server should give up on us and close the connection, which shows up as EOF here.
+    let mut buf = [0u8; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn stalled_header_drops_connection() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33479".parse().unwrap()],
+        read_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:33479").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // Send the first few bytes of a header, then never send the rest.
+    let mut header = Vec::new();
+    Header::new(100, 100).sync_write(&mut header).unwrap();
+    stream.write_all(&header[..3]).unwrap();
+
+    let start = std::time::Instant::now();
+
+    let mut buf = [0u8; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn silent_peer_dropped_after_idle_timeout() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33480".parse().unwrap()],
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    // Connect and never send anything.
+    let mut stream = TcpStream::connect("127.0.0.1:33480").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    let start = std::time::Instant::now();
+
+    let mut buf = [0u8; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn oversized_packet_terminates_connection() {
+    let mut buf = Vec::new();
+    Header::new(2048, 16).sync_write(&mut buf).unwrap();
+    assert!(matches!(
+        Header::sync_read(&mut &buf[..], 1024),
+        Err(HeaderError::TooLarge {
+            len: 2048,
+            max: 1024
+        })
+    ));
+
+    let params = Params {
+        addrs: vec!["0.0.0.0:33478".parse().unwrap()],
+        max_packet_size: 1024,
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut client = MockClient::new("127.0.0.1:33478".parse().unwrap());
+    client
+        .stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // Claim a ~4 GB packet, which the server must refuse to allocate.
+    Header::new(u32::MAX, u32::MAX)
+        .sync_write(&mut client.stream)
+        .unwrap();
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+    assert!(packet.fatal);
+
+    // The server hangs up after telling us why.
+    let mut buf = [0u8; 1];
+    assert_eq!(client.stream.read(&mut buf).unwrap(), 0);
+
+    networker.stop().await.unwrap();
+}
+
+#[test]
+fn compressor_write_timeout() {
+    // A writer that never makes progress.
+    struct Stalled;
+
+    impl tokio::io::AsyncWrite for Stalled {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()))
+        .with_write_timeout(Some(Duration::from_millis(50)));
+    let packet = GenerateChunk {
+        request_id: 1.into(),
+        generator_id: 2.into(),
+        pos: na::vector![3, 4],
+    }
+    .to_bincode()
+    .unwrap();
+
+    let result = runtime.block_on(compressor.write(&packet, &mut Stalled));
+    assert!(matches!(result, Err(CompressorError::TimedOut(_))));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn connection_over_duplex() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (read, write) = tokio::io::split(server);
+
+    let id = ConnectionId::new("0.0.0.0:0".parse().unwrap(), 0);
+    let mut conn = Connection::from_parts(Box::new(read), Box::new(write), id, &Params::default());
+    conn.run();
+
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()));
+
+    let request = GenerateChunk {
+        request_id: 31.into(),
+        generator_id: 4.into(),
+        pos: na::vector![1, 2],
+    };
+    compressor
+        .write(&request.to_bincode().unwrap(), &mut client)
+        .await
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let received = conn.incoming().await.collect::<Vec<_>>();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].to_packet::<GenerateChunk>().unwrap(), request);
+
+    let reply = packets::ConfirmGeneratorAddition::new(31.into(), 4.into());
+    conn.send_packet(&reply).await.unwrap();
+
+    let buf = compressor.read(&mut client).await.unwrap();
+    assert_eq!(
+        buf.to_packet::<packets::ConfirmGeneratorAddition>()
+            .unwrap(),
+        reply
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_connection_sleeps() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (read, write) = tokio::io::split(server);
+
+    let params = Params {
+        read_timeout: None,
+        ..Default::default()
+    };
+
+    let id = ConnectionId::new("0.0.0.0:0".parse().unwrap(), 0);
+    let mut conn = Connection::from_parts(Box::new(read), Box::new(write), id, &params);
+    conn.run();
+
+    // Paused time only advances when every task is waiting, so a connection task that spins while idle would
+    // keep this sleep from ever finishing.
+    let started = std::time::Instant::now();
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The writer still wakes up when there's something to write.
+    let reply = packets::ConfirmGeneratorAddition::new(31.into(), 4.into());
+    conn.send_packet(&reply).await.unwrap();
+
+    let buf = Compressor::new(CompressionAlgo::Zlib(Compression::best()))
+        .read(&mut client)
+        .await
+        .unwrap();
+    assert_eq!(
+        buf.to_packet::<packets::ConfirmGeneratorAddition>()
+            .unwrap(),
+        reply
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_heartbeats_terminate_connection() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (read, write) = tokio::io::split(server);
+
+    let params = Params {
+        heartbeat_interval: Some(Duration::from_secs(1)),
+        heartbeat_timeout: Duration::from_secs(3),
+        ..Default::default()
+    };
+
+    let id = ConnectionId::new("0.0.0.0:0".parse().unwrap(), 0);
+    let mut conn = Connection::from_parts(Box::new(read), Box::new(write), id, &params);
+    conn.run();
+
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()));
+
+    // Answering heartbeats keeps the connection alive for as long as we like.
+    for nonce in 1..=5 {
+        let heartbeat = compressor
+            .read(&mut client)
+            .await
+            .unwrap()
+            .to_packet::<Heartbeat>()
+            .unwrap();
+        assert_eq!(heartbeat.nonce, nonce);
+
+        compressor
+            .write(&heartbeat.to_bincode().unwrap(), &mut client)
+            .await
+            .unwrap();
+    }
+
+    // Echoed heartbeats are handled by the connection, not passed on as incoming packets.
+    assert_eq!(conn.incoming().await.count(), 0);
+
+    // Once we stop answering, the connection is terminated after 3 heartbeats go unanswered.
+    let mut unanswered = 0;
+    let error = loop {
+        let buf = compressor.read(&mut client).await.unwrap();
+        match buf.to_packet::<Heartbeat>() {
+            Ok(_) => unanswered += 1,
+            Err(_) => break buf.to_packet::<packets::ProtocolError>().unwrap(),
+        }
+    };
+
+    assert_eq!(unanswered, 3);
+    assert!(error.fatal);
+    assert!(matches!(error.kind, ProtocolErrorKind::Terminated { .. }));
+
+    let mut buf = [0u8; 1];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn forged_heartbeat_nonce_ignored() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (read, write) = tokio::io::split(server);
+
+    let params = Params {
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        heartbeat_timeout: Duration::from_millis(300),
+        ..Default::default()
+    };
+
+    let id = ConnectionId::new("0.0.0.0:0".parse().unwrap(), 0);
+    let mut conn = Connection::from_parts(Box::new(read), Box::new(write), id, &params);
+    conn.run();
+
+    let compressor = Compressor::new(CompressionAlgo::Zlib(Compression::best()));
+
+    // Acknowledging a heartbeat from the far future must not count as answering the ones we're actually sent.
+    compressor
+        .write(
+            &Heartbeat { nonce: u64::MAX }.to_bincode().unwrap(),
+            &mut client,
+        )
+        .await
+        .unwrap();
+
+    let mut unanswered = 0;
+    let error = loop {
+        let buf = compressor.read(&mut client).await.unwrap();
+        match buf.to_packet::<Heartbeat>() {
+            Ok(_) => unanswered += 1,
+            Err(_) => break buf.to_packet::<packets::ProtocolError>().unwrap(),
+        }
+    };
+
+    assert_eq!(unanswered, 3);
+    assert!(matches!(error.kind, ProtocolErrorKind::Terminated { .. }));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn dropped_connection_stops_tasks() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (read, write) = tokio::io::split(server);
+
+    let id = ConnectionId::new("0.0.0.0:0".parse().unwrap(), 0);
+    let mut conn = Connection::from_parts(Box::new(read), Box::new(write), id, &Params::default());
+    conn.run();
+
+    let clone = conn.clone();
+    drop(conn);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // A clone is still alive, so the tasks (and with them the transport) must be too.
+    let mut buf = [0u8; 1];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), client.read(&mut buf))
+            .await
+            .is_err()
+    );
+
+    drop(clone);
+
+    // Once the tasks are aborted the server's end of the transport is dropped, so the client sees EOF.
+    let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf))
+        .await
+        .expect("connection tasks kept running after the connection was dropped")
+        .unwrap();
+    assert_eq!(read, 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn terminated_error_is_last_packet() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33450".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut client = MockClient::new("127.0.0.1:33450".parse().unwrap());
+    let local_addr = client.stream.local_addr().unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let conn = networker.connections_from(local_addr).await.remove(0);
+
+    // Queue up a bunch of packets right before terminating, so they're still in flight.
+    for request_id in 0..32 {
+        conn.send_packet(&packets::ConfirmGeneratorAddition::new(
+            request_id.into(),
+            1.into(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    networker.stop().await.unwrap();
+
+    for request_id in 0..32 {
+        let packet = client
+            .read_packet::<packets::ConfirmGeneratorAddition>()
+            .unwrap();
+        assert_eq!(packet.request_id, request_id.into());
+    }
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+    assert!(packet.fatal);
+    assert!(matches!(packet.kind, ProtocolErrorKind::Terminated { .. }));
+
+    // Nothing comes after the terminated error.
+    let mut buf = [0u8; 1];
+    assert_eq!(client.stream.read(&mut buf).unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn stopped_networker_releases_address() {
+    let params = Params {
+        addrs: vec!["127.0.0.1:33475".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut client = MockClient::new("127.0.0.1:33475".parse().unwrap());
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    tokio::time::timeout(Duration::from_secs(5), networker.clone().stop())
+        .await
+        .expect("stopping the networker hung")
+        .unwrap();
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+    assert!(matches!(packet.kind, ProtocolErrorKind::Terminated { .. }));
+    assert!(networker.incoming().await.next().is_none());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The accept loop has exited and dropped its listener, so the address can be bound again.
+    tokio::net::TcpListener::bind("127.0.0.1:33475")
+        .await
+        .unwrap();
+}
+
+#[test]
+fn unified_packet_chunk_round_trip() {
+    let mut chunk = Chunk::new(77.into(), na::vector![-6, 2], -64, 320);
+    chunk.set(Spaces::Cs([10i32, 120, 8]), 80.into());
+
+    // The runtime's packets are the ones from procgen-common, so what the runtime encodes is exactly what the
+    // plugin natives decode (and vice versa).
+    let packet = ReplyChunk {
+        request_id: 12.into(),
+        chunk: chunk.clone(),
+    };
+    let buf = packet.to_bincode().unwrap();
+
+    let decoded = buf.to_packet::<common::packets::ReplyChunk>().unwrap();
+    assert_eq!(decoded.request_id, 12.into());
+    assert_eq!(decoded.chunk, chunk);
+
+    let parsed = packets::parse_dyn(&buf).unwrap();
+    let parsed = parsed.downcast_ref::<ReplyChunk>().unwrap();
+    assert_eq!(parsed.chunk, chunk);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn networker_send() {
+    let params = Params {
+        addrs: vec!["0.0.0.0:33446".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut networker = Networker::new(params);
+    networker.run().await.unwrap();
+
+    let mut client = MockClient::new("127.0.0.1:33446".parse::<SocketAddr>().unwrap());
+
+    let generate_chunk_packet = GenerateChunk {
+        request_id: 560.into(),
+        generator_id: 4.into(),
+        pos: na::vector![-6, 2],
+    };
+
+    client.send_packet(&generate_chunk_packet).unwrap();
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut chunk = Chunk::new(77.into(), na::vector![-6, 2], -64, 320);
+    chunk.set(Spaces::Cs([10i32, 120, 8]), 80.into());
+    chunk.set(Spaces::Cs([6i32, -20, 9]), 92.into());
+
+    for (conn, raw_packet) in networker.incoming().await {
+        let packet = raw_packet
+            .as_ref()
+            .unwrap()
+            .downcast_ref::<GenerateChunk>()
+            .unwrap();
+
+        let chunk_packet = ReplyChunk {
+            request_id: packet.request_id,
+            chunk: chunk.clone(),
+        };
+
+        conn.send_packet(&chunk_packet).await.unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let received_packet = client.read_packet::<ReplyChunk>().unwrap();
+
+    assert_eq!(received_packet.request_id, 560.into());
+    assert_eq!(received_packet.chunk, chunk);
+
+    networker.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn end_to_end_server_test() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33443".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    let (mut client, generator_id) =
+        start_with_generator::<MockGenerator>(&mut server, 33443).await;
+
+    client
+        .send_packet(&packets::GenerateChunk {
+            request_id: 420.into(),
+            generator_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let packet = client.read_packet::<packets::ReplyChunk>().unwrap();
+    assert_eq!(packet.request_id, 420.into());
+
+    for x in 0..16 {
+        for z in 0..16 {
+            assert_eq!(
+                packet.chunk.get(Spaces::Cs([x, -64i32, z])),
+                Some(&BlockId::new(80))
+            );
+        }
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn client_generates_chunks() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33481".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<MockGenerator>().await.unwrap();
+    server.run().await;
+
+    let mut client = Client::connect("127.0.0.1:33481".parse().unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        client.generators().await.unwrap(),
+        vec![MockGenerator::NAME.to_string()]
+    );
+
+    let generator_id = client
+        .add_generator(MockGenerator::NAME, -64, 320, 21.into(), Parameters::new())
+        .await
+        .unwrap();
+
+    let chunk = client
+        .generate_chunk(generator_id, na::vector![6, 4])
+        .await
+        .unwrap();
+    assert_eq!(chunk.pos(), na::vector![6, 4]);
+    assert_eq!(
+        chunk.get(Spaces::Cs([3, -64i32, 9])),
+        Some(&BlockId::new(80))
+    );
+
+    // Errors sent by the server are returned instead of the reply.
+    let error = client
+        .add_generator(MockGenerator::NAME, 320, -64, 21.into(), Parameters::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ClientError::Protocol(ref e) if !e.fatal));
+
+    // The client is still usable after a gentle error.
+    assert!(client.health_check().await.is_ok());
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn client_reports_unknown_generator() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33489".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<MockGenerator>().await.unwrap();
+    server.run().await;
+
+    let mut client = Client::connect("127.0.0.1:33489".parse().unwrap())
+        .await
+        .unwrap();
+
+    let error = client
+        .generate_chunk(42.into(), na::vector![6, 4])
+        .await
+        .unwrap_err();
+    match error {
+        ClientError::Protocol(ProtocolError {
+            kind: ProtocolErrorKind::GeneratorNotFound { generator_id, .. },
+            fatal: false,
+        }) => assert_eq!(generator_id, 42.into()),
+        error => panic!("unexpected error: {error}"),
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn client_times_out() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33490".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<SleepyGenerator>().await.unwrap();
+    server.run().await;
+
+    let mut client = Client::connect("127.0.0.1:33490".parse().unwrap())
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50));
+
+    let generator_id = client
+        .add_generator(
+            SleepyGenerator::NAME,
+            -64,
+            320,
+            21.into(),
+            Parameters::new(),
+        )
+        .await
+        .unwrap();
+
+    let error = client
+        .generate_chunk(generator_id, na::vector![6, 4])
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ClientError::TimedOut(_)));
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn removed_generator_cant_be_added() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33482".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<MockGenerator>().await.unwrap();
+    server.run().await;
+
+    let mut client = Client::connect("127.0.0.1:33482".parse().unwrap())
+        .await
+        .unwrap();
+
+    let generator_id = client
+        .add_generator(MockGenerator::NAME, -64, 320, 21.into(), Parameters::new())
+        .await
+        .unwrap();
+
+    assert!(server.remove_generator(MockGenerator::NAME).await);
+    assert!(!server.remove_generator(MockGenerator::NAME).await);
+    assert!(!server.has_generator(MockGenerator::NAME).await);
+    assert!(client.generators().await.unwrap().is_empty());
+
+    let error = client
+        .add_generator(MockGenerator::NAME, -64, 320, 21.into(), Parameters::new())
+        .await
+        .unwrap_err();
+    match error {
+        ClientError::Protocol(ProtocolError {
+            kind: ProtocolErrorKind::UnknownGenerator { name, .. },
+            fatal: false,
+        }) => assert_eq!(name, MockGenerator::NAME),
+        error => panic!("unexpected error: {error}"),
+    }
+
+    // Generators that were added before the removal are gone too.
+    let error = client
+        .generate_chunk(generator_id, na::vector![1, 1])
+        .await
+        .unwrap_err();
+    match error {
+        ClientError::Protocol(ProtocolError {
+            kind:
+                ProtocolErrorKind::GeneratorNotFound {
+                    generator_id: missing_id,
+                    ..
+                },
+            fatal: false,
+        }) => assert_eq!(missing_id, generator_id),
+        error => panic!("unexpected error: {error}"),
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn server_generator_queries() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33464".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    assert!(!server.has_generator(MockGenerator::NAME).await);
+    assert!(server.generator_names().await.is_empty());
+
+    server.add_generator::<MockGenerator>().await.unwrap();
+
+    assert!(server.has_generator(MockGenerator::NAME).await);
+    assert!(!server.has_generator("NOT_A_GENERATOR").await);
+    assert_eq!(
+        server.generator_names().await,
+        vec![MockGenerator::NAME.to_string()]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn failed_generation_reported() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33465".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    let (mut client, generator_id) =
+        start_with_generator::<FailingGenerator>(&mut server, 33465).await;
+
+    client
+        .send_packet(&packets::GenerateChunk {
+            request_id: 421.into(),
+            generator_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+
+    assert!(!packet.fatal);
+    match packet.kind {
+        ProtocolErrorKind::ChunkGenerationFailure {
+            generator_id: failed_generator_id,
+            request_id,
+            ..
+        } => {
+            assert_eq!(failed_generator_id, generator_id);
+            assert_eq!(request_id, 421.into());
+        }
+        _ => panic!("unexpected error kind"),
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn unknown_generator_reported() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33486".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    let (mut client, generator_id) =
+        start_with_generator::<MockGenerator>(&mut server, 33486).await;
+
+    // Any ID except the one the server gave us.
+    let unknown_id = GeneratorId::from(u32::from(generator_id).wrapping_add(1));
+
+    client
+        .send_packet(&packets::GenerateChunk {
+            request_id: 423.into(),
+            generator_id: unknown_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+    assert!(!packet.fatal);
+    assert_eq!(
+        packet.kind,
+        ProtocolErrorKind::GeneratorNotFound {
+            generator_id: unknown_id,
+            request_id: 423.into(),
+        }
+    );
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn generated_chunk_delivered_promptly() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33466".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    let (mut client, generator_id) =
+        start_with_generator::<MockGenerator>(&mut server, 33466).await;
+
+    let start = std::time::Instant::now();
+
+    client
+        .send_packet(&packets::GenerateChunk {
+            request_id: 422.into(),
+            generator_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    // No sleeping here, the read blocks until the chunk arrives.
+    let packet = client.read_packet::<packets::ReplyChunk>().unwrap();
+
+    assert_eq!(packet.request_id, 422.into());
+    // Generous bound so a loaded machine doesn't fail this, it's only here to catch the chunk sitting undelivered.
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn zero_height_generator_rejected() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33469".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    server.add_generator::<MockGenerator>().await.unwrap();
+
+    server.run().await;
+
+    let mut client = MockClient::new("127.0.0.1:33469".parse().unwrap());
+
+    client
+        .send_packet(
+            &add_generator_request::<MockGenerator>()
+                .request_id(501.into())
+                .region(64, 64)
+                .build(),
+        )
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+
+    assert!(!packet.fatal);
+    match packet.kind {
+        ProtocolErrorKind::InvalidRegion {
+            request_id,
+            details,
+        } => {
+            assert_eq!(request_id, 501.into());
+            assert!(details.contains("max height"));
+        }
+        _ => panic!("unexpected error kind"),
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn health_check_answered() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33472".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.run().await;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = MockClient::new("127.0.0.1:33472".parse().unwrap());
+    client
+        .send_packet(&packets::HealthCheck { nonce: 0xC0FFEE })
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let reply = client.read_packet::<packets::HealthOk>().unwrap();
+    assert_eq!(reply.nonce, 0xC0FFEE);
+    assert!(reply.uptime_ms >= 100, "uptime was {}ms", reply.uptime_ms);
+    assert!(reply.uptime_ms < 60_000, "uptime was {}ms", reply.uptime_ms);
+    assert_eq!(reply.active_requests, 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn generators_listed_on_request() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33477".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    server.add_generator::<MockGenerator>().await.unwrap();
+    server.add_generator::<SlowGenerator>().await.unwrap();
+
+    server.run().await;
+
+    let mut client = MockClient::new("127.0.0.1:33477".parse().unwrap());
+    client
+        .send_packet(&packets::RequestGenerators {
+            request_id: 12.into(),
+        })
+        .unwrap();
+
+    let list = client.read_packet::<packets::ListGenerators>().unwrap();
+    assert_eq!(list.request_id, 12.into());
+
+    let mut expected = vec![
+        MockGenerator::NAME.to_string(),
+        SlowGenerator::NAME.to_string(),
+    ];
+    expected.sort_unstable();
+    assert_eq!(list.generators, expected);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn duplicate_generator_rejected() {
+    let mut server = Server::new(ServerParams::default());
+
+    server.add_generator::<MockGenerator>().await.unwrap();
+
+    let error = server.add_generator::<MockGenerator>().await.unwrap_err();
+    assert_eq!(error.0, MockGenerator::NAME);
+
+    // the failed registration didn't affect the other generators
+    server.add_generator::<SlowGenerator>().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn server_uses_provided_pool() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33470".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::with_pool(params, ThreadPool::new(2));
+
+    let (mut client, generator_id) =
+        start_with_generator::<SlowGenerator>(&mut server, 33470).await;
+
+    for request_id in 0..8 {
+        client
+            .send_packet(&packets::GenerateChunk {
+                request_id: request_id.into(),
+                generator_id,
+                pos: na::vector![6i32, 4],
+            })
+            .unwrap();
+    }
+
+    for _ in 0..8 {
+        client.read_packet::<packets::ReplyChunk>().unwrap();
+    }
+
+    assert!(MAX_ACTIVE.load(Ordering::SeqCst) <= 2);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn excess_requests_rejected_when_busy() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33471".parse().unwrap()],
+        max_pending: 2,
+        ..Default::default()
+    };
+
+    let mut server = Server::with_pool(params, ThreadPool::new(2));
+
+    let (mut client, generator_id) =
+        start_with_generator::<SleepyGenerator>(&mut server, 33471).await;
+
+    for request_id in 0..4 {
+        client
+            .send_packet(&packets::GenerateChunk {
+                request_id: request_id.into(),
+                generator_id,
+                pos: na::vector![6i32, 4],
+            })
+            .unwrap();
+    }
+
+    let mut replied = Vec::new();
+    let mut busy = Vec::new();
+
+    for _ in 0..4 {
+        let buf = client.read_buffer().unwrap();
+
+        match buf.id() {
+            ReplyChunk::ID => replied.push(buf.to_packet::<ReplyChunk>().unwrap().request_id),
+            packets::ProtocolError::ID => {
+                match buf.to_packet::<packets::ProtocolError>().unwrap() {
+                    packets::ProtocolError {
+                        kind: ProtocolErrorKind::ServerBusy { request_id, .. },
+                        fatal: false,
+                    } => busy.push(request_id),
+                    error => panic!("unexpected error {error:?}"),
+                }
+            }
+            id => panic!("unexpected packet ID {id}"),
+        }
+    }
+
+    replied.sort_by_key(|id| id.0);
+    busy.sort_by_key(|id| id.0);
+
+    // The first two requests fill up the queue and still complete, the rest are turned away.
+    assert_eq!(replied, vec![0.into(), 1.into()]);
+    assert_eq!(busy, vec![2.into(), 3.into()]);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn cancelled_request_not_replied_to() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33476".parse().unwrap()],
+        ..Default::default()
+    };
+
+    // A single worker, so the second request is still queued while the first is generating.
+    let mut server = Server::with_pool(params, ThreadPool::new(1));
+
+    let (mut client, generator_id) =
+        start_with_generator::<SleepyGenerator>(&mut server, 33476).await;
+
+    for request_id in 0..2 {
+        client
+            .send_packet(&packets::GenerateChunk {
+                request_id: request_id.into(),
+                generator_id,
+                pos: na::vector![6i32, 4],
+            })
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    client
+        .send_packet(&packets::CancelRequest {
+            request_id: 1.into(),
+        })
+        .unwrap();
+
+    let ack = client.read_packet::<packets::AckRequest>().unwrap();
+    assert_eq!(ack.request_id, 1.into());
+    assert_eq!(ack.info, "cancelled");
+
+    let reply = client.read_packet::<ReplyChunk>().unwrap();
+    assert_eq!(reply.request_id, 0.into());
+
+    // The cancelled request never gets a reply.
+    client
+        .stream
+        .set_read_timeout(Some(Duration::from_millis(750)))
+        .unwrap();
+    assert!(client.read_buffer().is_err());
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn disconnected_client_requests_cancelled() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33488".parse().unwrap()],
+        ..Default::default()
+    };
+
+    // A single worker, so everything after the first request is still queued when the client disconnects.
+    let mut server = Server::with_pool(params, ThreadPool::new(1));
+
+    let (mut client, generator_id) =
+        start_with_generator::<SleepyGenerator>(&mut server, 33488).await;
+
+    for request_id in 0..5 {
+        client
+            .send_packet(&packets::GenerateChunk {
+                request_id: request_id.into(),
+                generator_id,
+                pos: na::vector![6i32, 4],
+            })
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut other = MockClient::new("127.0.0.1:33488".parse().unwrap());
+    let start = std::time::Instant::now();
+
+    other
+        .send_packet(&packets::GenerateChunk {
+            request_id: 5.into(),
+            generator_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    let reply = other.read_packet::<ReplyChunk>().unwrap();
+    assert_eq!(reply.request_id, 5.into());
+
+    // Only the first request (which was already generating) and ours should be generated. If the disconnected
+    // client's queued requests were generated too, we'd be waiting over a second.
+    assert!(start.elapsed() < Duration::from_millis(900));
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn request_hook_fires_per_request() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33468".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    {
+        let seen = seen.clone();
+        server.set_request_hook(move |_, packet| seen.lock().unwrap().push(packet.request_id));
+    }
+
+    let (mut client, generator_id) =
+        start_with_generator::<MockGenerator>(&mut server, 33468).await;
+
+    // Only chunk requests go through the hook.
+    assert!(seen.lock().unwrap().is_empty());
+
+    for request_id in [423, 424] {
+        client
+            .send_packet(&packets::GenerateChunk {
+                request_id: request_id.into(),
+                generator_id,
+                pos: na::vector![6i32, 4],
+            })
+            .unwrap();
+
+        client.read_packet::<packets::ReplyChunk>().unwrap();
+    }
+
+    assert_eq!(*seen.lock().unwrap(), vec![423.into(), 424.into()]);
+
+    server.stop().await.unwrap();
+}
+
+#[cfg(debug_assertions)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn misplaced_chunk_rejected_in_debug() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33467".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+
+    let (mut client, generator_id) =
+        start_with_generator::<MisplacedGenerator>(&mut server, 33467).await;
+
+    client
+        .send_packet(&packets::GenerateChunk {
+            request_id: 423.into(),
+            generator_id,
+            pos: na::vector![6i32, 4],
+        })
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let packet = client.read_packet::<packets::ProtocolError>().unwrap();
+
+    assert!(!packet.fatal);
+    assert!(matches!(
+        packet.kind,
+        ProtocolErrorKind::ChunkGenerationFailure { .. }
+    ));
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn server_stopping() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33463".parse().unwrap()],
+        compression: CompressionAlgo::Zlib(Compression::best()),
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<MockGenerator>().await.unwrap();
+    server.run().await;
+
+    let t1 = std::thread::spawn(|| {
+        let mut client1 = MockClient::new("127.0.0.1:33463".parse().unwrap());
+
+        client1
+            .send_packet(
+                &add_generator_request::<MockGenerator>()
+                    .request_id(101.into())
+                    .build(),
+            )
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(250));
+
+        let packet1 = client1
+            .read_packet::<packets::ConfirmGeneratorAddition>()
+            .unwrap();
+
+        assert_eq!(packet1.request_id, 101.into());
+
+        client1
+    });
+
+    let t2 = std::thread::spawn(|| {
+        let mut client2 = MockClient::new("127.0.0.1:33463".parse().unwrap());
+
+        client2
+            .send_packet(
+                &add_generator_request::<MockGenerator>()
+                    .request_id(202.into())
+                    .region(-128, 320)
+                    .default_id(42.into())
+                    .build(),
+            )
+            .unwrap();
 
         std::thread::sleep(Duration::from_millis(250));
 
@@ -365,3 +2234,57 @@ async fn server_stopping() {
     t1.join().unwrap();
     t2.join().unwrap();
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn client_generates_chunk_deltas() {
+    let params = ServerParams {
+        addrs: vec!["0.0.0.0:33483".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let mut server = Server::new(params);
+    server.add_generator::<SeededGenerator>().await.unwrap();
+    server.run().await;
+
+    let mut client = Client::connect("127.0.0.1:33483".parse().unwrap())
+        .await
+        .unwrap();
+
+    let mut ids = Vec::new();
+    for (seed, min_height) in [(5, -64), (6, -64), (6, 0)] {
+        let params = Parameters::new().with_seed(seed);
+        ids.push(
+            client
+                .add_generator(SeededGenerator::NAME, min_height, 320, 1.into(), params)
+                .await
+                .unwrap(),
+        );
+    }
+    let (base_id, target_id, short_id) = (ids[0], ids[1], ids[2]);
+
+    let pos = na::vector![-2, 7];
+    let mut base = client.generate_chunk(base_id, pos).await.unwrap();
+    let target = client.generate_chunk(target_id, pos).await.unwrap();
+
+    let changes = client
+        .generate_chunk_delta(target_id, base_id, pos)
+        .await
+        .unwrap();
+    assert_eq!(Some(changes.clone()), target.diff(&base));
+
+    base.apply_delta(&changes);
+    assert_eq!(base, target);
+
+    // Chunks of different heights can't be diffed, so the request fails instead of getting a reply.
+    let error = client
+        .generate_chunk_delta(target_id, short_id, pos)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        ClientError::Protocol(ref e)
+            if matches!(e.kind, ProtocolErrorKind::ChunkGenerationFailure { .. })
+    ));
+
+    server.stop().await.unwrap();
+}
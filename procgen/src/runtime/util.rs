@@ -1,16 +1,18 @@
 use common::{ConnectionId, GeneratorId, RequestId};
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq)]
+/// Identifies a request from a client, i.e., the request's ID and the connection it was sent over.
+/// Request IDs are chosen by clients, so they're only unique per connection.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct RequestIdent {
     pub request_id: RequestId,
-    pub client_id: ConnectionId,
+    pub connection_id: ConnectionId,
 }
 
 impl RequestIdent {
-    pub fn new(request_id: RequestId, client_id: ConnectionId) -> Self {
+    pub fn new(request_id: RequestId, connection_id: ConnectionId) -> Self {
         Self {
             request_id,
-            client_id,
+            connection_id,
         }
     }
 
@@ -31,7 +33,8 @@ impl From<GenerationIdent> for RequestId {
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq)]
+/// Identifies a chunk generation request, i.e., the request and the generator that should handle it.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct GenerationIdent {
     pub request_ident: RequestIdent,
     pub generator_id: GeneratorId,
@@ -54,7 +57,7 @@ impl From<GenerationIdent> for GeneratorId {
 
 impl From<RequestIdent> for ConnectionId {
     fn from(i: RequestIdent) -> Self {
-        i.client_id
+        i.connection_id
     }
 }
 
@@ -69,3 +72,22 @@ impl From<GenerationIdent> for RequestIdent {
         i.request_ident
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::{ConnectionId, GeneratorId, RequestId};
+
+    use super::{GenerationIdent, RequestIdent};
+
+    #[test]
+    fn idents_round_trip() {
+        let connection_id = ConnectionId::new("127.0.0.1:4432".parse().unwrap(), 7);
+        let request_ident = RequestIdent::new(RequestId(12), connection_id);
+        let generation_ident = request_ident.generation_ident(GeneratorId(3));
+
+        assert_eq!(RequestIdent::from(generation_ident), request_ident);
+        assert_eq!(RequestId::from(generation_ident), RequestId(12));
+        assert_eq!(ConnectionId::from(generation_ident), connection_id);
+        assert_eq!(GeneratorId::from(generation_ident), GeneratorId(3));
+    }
+}
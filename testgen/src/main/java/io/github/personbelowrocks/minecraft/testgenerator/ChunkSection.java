@@ -2,12 +2,18 @@ package io.github.personbelowrocks.minecraft.testgenerator;
 
 public class ChunkSection {
     private int[][][] internal;
+    private long defaultId;
 
-    public ChunkSection(int[][][] arr) {
+    public ChunkSection(int[][][] arr, long defaultId) {
         internal = arr;
+        this.defaultId = defaultId;
     }
 
     public int[][][] getInternal() {
         return internal;
     }
+
+    public long getDefaultId() {
+        return defaultId;
+    }
 }
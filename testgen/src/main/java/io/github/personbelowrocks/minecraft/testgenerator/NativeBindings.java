@@ -9,8 +9,16 @@ public class NativeBindings {
         } catch (UnsatisfiedLinkError e) {
             System.load(NativeGen.getLibPath());
         }
+
+        init();
     }
 
+    /**
+     * Look up and cache the classes the natives need.
+     * Throws an {@link IllegalStateException} naming the class if one of them is missing.
+     */
+    private static native void init();
+
     public static native Object decodePacket(byte[] bytes, long sizeHint);
 
     public static native byte[] encodePacket(short id, Object packet);
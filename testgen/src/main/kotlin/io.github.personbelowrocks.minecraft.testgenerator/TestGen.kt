@@ -23,6 +23,24 @@ import kotlin.math.pow
 
 val logger = Bukkit.getLogger()
 
+// Version of the framing protocol, this must match procgen_common::PROTOCOL_VERSION.
+const val PROTOCOL_VERSION = 3
+
+// ID of zlib in packet headers, the only compression algorithm the natives can decompress.
+const val ZLIB_ALGORITHM = 0
+
+fun checkProtocolVersion(version: Int) {
+    if (version != PROTOCOL_VERSION) {
+        throw IOException("mismatched protocol version (expected $PROTOCOL_VERSION, got $version)")
+    }
+}
+
+fun checkCompressionAlgorithm(algorithm: Int) {
+    if (algorithm != ZLIB_ALGORITHM) {
+        throw IOException("unsupported compression algorithm $algorithm (only zlib is supported)")
+    }
+}
+
 
 class ChunkEvents(private val cache: HashMap<ChunkPosition, CachedChunk>, private val batchSize: Int, private val nLatest: Int, private val cleanupInterval: Int): Listener {
     private val latest = HashSet<ChunkPosition>()
@@ -149,6 +167,8 @@ class Networker(inStream: InputStream, outStream: OutputStream) {
         override fun run() {
             while (true) {
 
+                checkProtocolVersion(stream.read())
+                checkCompressionAlgorithm(stream.read())
                 val compressedSize = stream.readNBytes(4).getUIntAt(0)
                 val decompressedSize = stream.readNBytes(4).getUIntAt(0)
                 val compressedBuffer = stream.readNBytes(compressedSize.toInt())
@@ -216,6 +236,8 @@ class MyGenerator(val plugin: JavaPlugin, address: String, port: UShort) : Chunk
             println("finished writing buffer")
 
             println("trying to read response from server...")
+            checkProtocolVersion(socket.getInputStream().read())
+            checkCompressionAlgorithm(socket.getInputStream().read())
             val compressedSize = socket.getInputStream().readNBytes(4).getUIntAt(0)
             println("got packet with compressed size of $compressedSize")
             val decompressedSize = socket.getInputStream().readNBytes(4).getUIntAt(0)